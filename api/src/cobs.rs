@@ -0,0 +1,347 @@
+/*!
+  Consistent overhead byte stuffing (COBS) framing.
+
+  Frames are stuffed so that they contain no zero bytes and are terminated by a
+  single `FRAME_DELIMITER`. This makes frame boundaries unambiguous regardless of
+  the content of the payload, at the cost of one byte per 254 bytes of payload
+  plus the delimiter.
+*/
+
+use data::{Message, EncodingError, DecodingContext, DecodingError, Resync};
+
+pub const FRAME_DELIMITER: u8 = 0;
+
+/// The largest payload that can be decoded from a frame
+pub const MAX_PAYLOAD_SIZE: usize = 256;
+
+/**
+  Wraps any `Message` in a COBS frame
+*/
+#[derive(Debug, PartialEq, Clone)]
+pub struct Cobs<M>(pub M);
+
+/**
+  Returns the length of the first frame in `bytes`, including the delimiter, or
+  `None` if the frame is not yet complete
+*/
+pub fn frame_len(bytes: &[u8]) -> Option<usize> {
+    bytes.iter().position(|b| *b == FRAME_DELIMITER).map(|pos| pos + 1)
+}
+
+/**
+  Encodes `message` into a COBS frame without requiring a separate scratch buffer.
+
+  The message is first encoded at an offset in `buff` and then stuffed towards
+  the start of the buffer. The offset is large enough for the stuffed data to
+  never overtake the bytes that are still to be read.
+*/
+pub fn encode_message<M: Message<M>>(message: &M, buff: &mut [u8]) -> Result<usize, EncodingError> {
+    encode_with(buff, |buff| message.encode(buff))
+}
+
+/**
+  Like `encode_message`, but the content of the frame is written by
+  `encode_content`
+*/
+pub fn encode_with<F>(buff: &mut [u8], encode_content: F) -> Result<usize, EncodingError>
+    where F: FnOnce(&mut [u8]) -> Result<usize, EncodingError>
+{
+    let offset = buff.len() / 254 + 2;
+    if buff.len() <= offset {
+        return Err(EncodingError::BufferToSmall);
+    }
+    let len = encode_content(&mut buff[offset..])?;
+
+    let mut code_index = 0;
+    let mut write = 1;
+    let mut code = 1u8;
+    for read in offset..offset + len {
+        let byte = buff[read];
+        if byte == 0 {
+            buff[code_index] = code;
+            code_index = write;
+            write += 1;
+            code = 1;
+        }
+        else {
+            buff[write] = byte;
+            write += 1;
+            code += 1;
+            if code == 0xff {
+                buff[code_index] = code;
+                code_index = write;
+                write += 1;
+                code = 1;
+            }
+        }
+    }
+
+    if write >= buff.len() {
+        return Err(EncodingError::BufferToSmall);
+    }
+    buff[code_index] = code;
+    buff[write] = FRAME_DELIMITER;
+    Ok(write + 1)
+}
+
+/**
+  Removes the stuffing from a frame without its delimiter, returning the amount
+  of bytes written to `output`
+*/
+pub fn unstuff(frame: &[u8], output: &mut [u8]) -> Result<usize, DecodingError> {
+    let mut read = 0;
+    let mut write = 0;
+    while read < frame.len() {
+        let code = frame[read] as usize;
+        if code == 0 || read + code > frame.len() {
+            return Err(DecodingError::MalformedFrame);
+        }
+        read += 1;
+
+        let block = &frame[read..read + code - 1];
+        if write + block.len() > output.len() {
+            return Err(DecodingError::MalformedFrame);
+        }
+        if block.contains(&FRAME_DELIMITER) {
+            return Err(DecodingError::MalformedFrame);
+        }
+        output[write..write + block.len()].copy_from_slice(block);
+        write += block.len();
+        read += block.len();
+
+        // Every block except full ones and the last one is followed by a zero
+        if code != 0xff && read < frame.len() {
+            if write >= output.len() {
+                return Err(DecodingError::MalformedFrame);
+            }
+            output[write] = 0;
+            write += 1;
+        }
+    }
+    Ok(write)
+}
+
+/**
+  Besides the stuffing overhead and the delimiter, `encode_message` needs one
+  byte of slack. Using this for a message that does not fit in
+  `MAX_PAYLOAD_SIZE` is a compile time error
+*/
+impl<M: Message<M>> Message<Self> for Cobs<M> {
+    const MAX_ENCODED_LEN: usize = {
+        assert!(
+            M::MAX_ENCODED_LEN <= MAX_PAYLOAD_SIZE,
+            "Message is too large to be decoded from a COBS frame"
+        );
+        M::MAX_ENCODED_LEN + M::MAX_ENCODED_LEN / 254 + 3
+    };
+
+    fn encode(&self, buff: &mut [u8]) -> Result<usize, EncodingError> {
+        encode_message(&self.0, buff)
+    }
+
+    fn decode(bytes: &[u8], context: DecodingContext) -> Result<(usize, Self), DecodingError> {
+        let len = frame_len(bytes).ok_or(DecodingError::EndOfBytes)?;
+
+        let mut payload = [0; MAX_PAYLOAD_SIZE];
+        let payload_len = unstuff(&bytes[..len - 1], &mut payload)?;
+
+        // The whole frame is available so running out of bytes means the
+        // frame was malformed
+        let (used_bytes, message) = M::decode(&payload[..payload_len], context)
+            .map_err(|e| match e {
+                DecodingError::EndOfBytes => DecodingError::MalformedFrame,
+                e => e
+            })?;
+
+        if used_bytes != payload_len {
+            return Err(DecodingError::MalformedFrame);
+        }
+
+        Ok((len, Cobs(message)))
+    }
+}
+
+/// Everything up to and including the next delimiter is dropped
+impl<M> Resync for Cobs<M> {
+    fn resync_offset(bytes: &[u8]) -> usize {
+        frame_len(bytes).unwrap_or(bytes.len())
+    }
+}
+
+
+#[cfg(test)]
+mod cobs_tests {
+    use super::*;
+    use data::{
+        encode_client_host_frame,
+        ClientHostFrame,
+        ClientHostMessage,
+        Reading,
+        ReadingBatch,
+        State,
+        StreamDecoder,
+        StreamError,
+        StreamEvent,
+        MAX_BATCH_SIZE
+    };
+
+    /// A message consisting of raw bytes to test the stuffing
+    #[derive(Debug, PartialEq)]
+    struct Raw {
+        data: [u8; 600],
+        len: usize,
+    }
+
+    impl Raw {
+        fn new(bytes: &[u8]) -> Self {
+            let mut data = [0; 600];
+            data[..bytes.len()].copy_from_slice(bytes);
+            Raw {data, len: bytes.len()}
+        }
+    }
+
+    impl Message<Self> for Raw {
+        const MAX_ENCODED_LEN: usize = 600;
+
+        fn encode(&self, buff: &mut [u8]) -> Result<usize, EncodingError> {
+            if buff.len() < self.len {
+                return Err(EncodingError::BufferToSmall);
+            }
+            buff[..self.len].copy_from_slice(&self.data[..self.len]);
+            Ok(self.len)
+        }
+
+        fn decode(bytes: &[u8], _context: DecodingContext) -> Result<(usize, Self), DecodingError> {
+            Ok((bytes.len(), Raw::new(bytes)))
+        }
+    }
+
+    fn encode_raw(bytes: &[u8], output: &mut [u8]) -> usize {
+        encode_message(&Raw::new(bytes), output).expect("Failed to encode")
+    }
+
+    #[test]
+    fn known_encodings() {
+        let mut buffer = [0xaa; 16];
+
+        let len = encode_raw(&[0], &mut buffer);
+        assert_eq!(&buffer[..len], &[1, 1, 0]);
+
+        let len = encode_raw(&[0, 0], &mut buffer);
+        assert_eq!(&buffer[..len], &[1, 1, 1, 0]);
+
+        let len = encode_raw(&[0x11, 0x22, 0, 0x33], &mut buffer);
+        assert_eq!(&buffer[..len], &[3, 0x11, 0x22, 2, 0x33, 0]);
+
+        let len = encode_raw(&[0x11, 0x22, 0x33, 0x44], &mut buffer);
+        assert_eq!(&buffer[..len], &[5, 0x11, 0x22, 0x33, 0x44, 0]);
+    }
+
+    #[test]
+    fn long_frames_round_trip() {
+        for &size in &[253, 254, 255, 256, 300] {
+            let mut data = [0; 300];
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = (i % 255) as u8 + 1;
+            }
+            data[100] = 0;
+
+            let mut buffer = [0; 320];
+            let len = encode_raw(&data[..size], &mut buffer);
+            assert_eq!(frame_len(&buffer[..len]), Some(len));
+            assert!(!buffer[..len - 1].contains(&FRAME_DELIMITER));
+
+            let mut output = [0; 300];
+            let decoded_len = unstuff(&buffer[..len - 1], &mut output).unwrap();
+            assert_eq!(&output[..decoded_len], &data[..size]);
+        }
+    }
+
+    #[test]
+    fn message_round_trip() {
+        // A timestamp containing the message prefix and zeros
+        let reading = Reading::new(0x00fe00fe, State::from_channels(&[true, false]));
+        let mut buffer = [0; 12];
+        let len = Cobs(ClientHostMessage::Reading(reading)).encode(&mut buffer).unwrap();
+
+        let context = DecodingContext {channel_count: 2};
+        assert_eq!(
+            Cobs::<ClientHostMessage>::decode(&buffer[..len], context),
+            Ok((len, Cobs(ClientHostMessage::Reading(reading))))
+        );
+        assert_eq!(
+            Cobs::<ClientHostMessage>::decode(&buffer[..len - 1], context),
+            Err(DecodingError::EndOfBytes)
+        );
+    }
+
+    #[test]
+    fn max_encoded_len_fits_any_message() {
+        let mut batch = ReadingBatch::new();
+        for i in 0..MAX_BATCH_SIZE as u32 {
+            batch.push(Reading::new(i.wrapping_mul(0xffff_ffff), State::new(32, !0))).unwrap();
+        }
+        let message = Cobs(ClientHostFrame {
+            sequence: 0xff,
+            message: ClientHostMessage::Readings(batch)
+        });
+
+        let mut buffer = [0; Cobs::<ClientHostFrame>::MAX_ENCODED_LEN];
+        let len = message.encode(&mut buffer).unwrap();
+        let context = DecodingContext {channel_count: 32};
+        assert_eq!(Cobs::<ClientHostFrame>::decode(&buffer[..len], context), Ok((len, message)));
+    }
+
+    #[test]
+    fn borrowed_frames_match_owned_ones() {
+        let frame = ClientHostFrame {
+            sequence: 7,
+            message: ClientHostMessage::CurrentTime(0x01000200)
+        };
+        let mut buffer = [0; 16];
+        let len = encode_with(&mut buffer, |buff| {
+            encode_client_host_frame(frame.sequence, &frame.message, buff)
+        }).unwrap();
+        assert_eq!(
+            Cobs::<ClientHostFrame>::decode(&buffer[..len], DecodingContext::default()),
+            Ok((len, Cobs(frame)))
+        );
+    }
+
+    #[test]
+    fn truncated_frame_is_malformed() {
+        let mut buffer = [0; 12];
+        let len = Cobs(ClientHostMessage::CurrentTime(0x01020304))
+            .encode(&mut buffer)
+            .unwrap();
+        // Drop a byte from the middle of the frame
+        buffer[len - 2] = FRAME_DELIMITER;
+
+        assert_eq!(
+            Cobs::<ClientHostMessage>::decode(&buffer[..len - 1], DecodingContext::default()),
+            Err(DecodingError::MalformedFrame)
+        );
+    }
+
+    #[test]
+    fn stream_decoder_skips_corrupt_frames() {
+        let mut buffer = [0; 32];
+        let len = Cobs(ClientHostMessage::CurrentTime(0x01fe0304)).encode(&mut buffer).unwrap();
+        // A valid frame with a corrupted byte, followed by a valid frame
+        buffer.copy_within(0..len, len);
+        buffer[1] ^= 0x40;
+
+        let mut decoder = StreamDecoder::<Cobs<ClientHostMessage>, 32>::new();
+        assert_eq!(decoder.push(&buffer[..2 * len]), 2 * len);
+
+        match decoder.pull() {
+            Some(StreamEvent::Resync(StreamError{offset: 0, skipped, ..})) => assert_eq!(skipped, len),
+            other => panic!("Expected resync, got {:?}", other)
+        }
+        assert_eq!(
+            decoder.pull(),
+            Some(StreamEvent::Message(Cobs(ClientHostMessage::CurrentTime(0x01fe0304))))
+        );
+        assert_eq!(decoder.pull(), None);
+    }
+}
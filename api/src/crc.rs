@@ -0,0 +1,37 @@
+/**
+  CRC-8 using the polynomial `x^8 + x^2 + x + 1` (0x07) with an initial value of 0.
+
+  Computed bit by bit rather than with a lookup table to save flash on the device
+*/
+pub fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in bytes {
+        crc ^= *byte;
+        for _ in 0..8 {
+            if crc & 0x80 != 0 {
+                crc = (crc << 1) ^ 0x07;
+            }
+            else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+
+#[cfg(test)]
+mod crc_tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        // The standard check value for CRC-8 (SMBUS)
+        assert_eq!(crc8(b"123456789"), 0xf4);
+    }
+
+    #[test]
+    fn empty_input() {
+        assert_eq!(crc8(&[]), 0);
+    }
+}
@@ -1,210 +1,1267 @@
+#[cfg(feature = "std")]
+use core::fmt;
+use core::marker::PhantomData;
+
+use heapless::{String, Vec};
+
+use crc::crc8;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
 const MESSAGE_PREFIX: u8 = 0xfe;
 
-#[derive(Debug, PartialEq, Clone)]
+/// Version of the protocol described in this module. Must be increased whenever
+/// the encoding of a message changes
+pub const PROTOCOL_VERSION: u8 = 17;
+
+/// The largest amount of channels a `State` can hold
+pub const MAX_CHANNELS: u8 = 32;
+
+/// The amount of raw samples in a `SampleBlock`
+pub const SAMPLE_BLOCK_SIZE: usize = 64;
+
+/**
+  The longest time between `CurrentTime` messages in milliseconds. The host
+  needs a timestamp at least every 2^31 ticks to track wraparounds, which is
+  about 29.8 seconds at 72 MHz
+*/
+pub const MAX_HEARTBEAT_MILLIS: u32 = 29_000;
+
+/**
+  The values of all channels at some point in time. Bit `n` of the channel word
+  holds the value of channel `n`.
+
+  The channel count is not encoded, it is the same for all states of a device
+  and is taken from the `DecodingContext` when decoding
+*/
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct State {
-    data: u8
+    data: u32,
+    channel_count: u8,
 }
 
 impl State {
-    pub fn new(channel1: bool, channel2: bool) -> Self {
+    /// Creates a state from a channel word. Bits above `channel_count` are ignored
+    pub fn new(channel_count: u8, data: u32) -> Self {
+        assert!(channel_count <= MAX_CHANNELS, "State can hold at most 32 channels");
         Self {
-            data: (channel1 as u8) | (channel2 as u8) << 1
+            data: data & State::channel_mask(channel_count),
+            channel_count
         }
     }
 
-    pub fn channel1(&self) -> bool {
-        self.data & 1 == 1
+    pub fn from_channels(values: &[bool]) -> Self {
+        let data = values.iter()
+            .enumerate()
+            .fold(0, |acc, (i, value)| acc | (*value as u32) << i);
+        Self::new(values.len() as u8, data)
+    }
+
+    pub fn channel(&self, n: u8) -> bool {
+        n < self.channel_count && (self.data >> n) & 1 == 1
+    }
+
+    pub fn channel_count(&self) -> u8 {
+        self.channel_count
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.data
+    }
+
+    /// The amount of bytes used to encode the channel word
+    fn data_bytes(channel_count: u8) -> usize {
+        (channel_count as usize).div_ceil(8)
+    }
+
+    fn channel_mask(channel_count: u8) -> u32 {
+        if channel_count >= 32 {
+            !0
+        }
+        else {
+            (1 << channel_count) - 1
+        }
     }
-    pub fn channel2(&self) -> bool {
-        (self.data >> 1) & 1 == 1
+}
+
+/**
+  A set of channels where bit `n` is set if channel `n` is in the set. Encoded
+  as a `VarInt` since mostly the low channels are used
+*/
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ChannelMask(pub u32);
+
+impl ChannelMask {
+    pub fn contains(&self, channel: u8) -> bool {
+        channel < 32 && (self.0 >> channel) & 1 == 1
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
-pub struct Reading {
-    pub state: State,
-    pub time: u32
+message_struct! {
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct Reading {
+        pub state: State,
+        pub time: u32,
+        // The channels whose edges caused the reading. A channel can be in the
+        // set without its value changing if a pulse was shorter than the time
+        // it took to read the state
+        pub changed: ChannelMask,
+    }
 }
 
 impl Reading {
-    pub fn new(time: u32, channel1: bool, channel2: bool) -> Self {
+    /// Creates a reading where no channels are marked as changed
+    pub fn new(time: u32, state: State) -> Self {
         Self {
-            state: State::new(channel1, channel2),
-            time
+            state,
+            time,
+            changed: ChannelMask::default()
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
-pub enum ClientHostMessage {
-    Reading(Reading),
-    FrequencyHertz(u32),
-    Reset(u8), // Reset the specified channel readings
-    CurrentTime(u32),
+/// The largest amount of readings that can be sent in a single `ReadingBatch`
+pub const MAX_BATCH_SIZE: usize = 16;
+
+/**
+  Several readings sent in a single frame to only pay for the frame header once.
+
+  The first reading is encoded in full and the following ones as the time since
+  the reading before them, so a batch can be decoded on its own.
+*/
+#[derive(Debug, Clone)]
+pub struct ReadingBatch {
+    readings: [Reading; MAX_BATCH_SIZE],
+    len: u8,
 }
 
-////////////////////////////////////////////////////////////////////////////////
-//                 Encoding and decoding functions
-////////////////////////////////////////////////////////////////////////////////
+impl ReadingBatch {
+    pub fn new() -> Self {
+        Self {
+            readings: [Reading::new(0, State::new(0, 0)); MAX_BATCH_SIZE],
+            len: 0
+        }
+    }
 
-/**
-  Decodes a `$enum_name` based on the `$prefix` and the payload ($bytes).
-  Each enum type is represented by `$prefix_value => (variant_name, inner_value_type)`
+    /// Adds a reading to the batch, returning it back if the batch is full
+    pub fn push(&mut self, reading: Reading) -> Result<(), Reading> {
+        if self.is_full() {
+            return Err(reading);
+        }
+        self.readings[self.len as usize] = reading;
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn readings(&self) -> &[Reading] {
+        &self.readings[..self.len as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len as usize == MAX_BATCH_SIZE
+    }
+}
+
+impl Default for ReadingBatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PartialEq for ReadingBatch {
+    fn eq(&self, other: &Self) -> bool {
+        self.readings() == other.readings()
+    }
+}
+
+/// Batches are serialized as a sequence of the readings they hold
+#[cfg(feature = "serde")]
+impl Serialize for ReadingBatch {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.readings())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for ReadingBatch {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let readings = Vec::<Reading, MAX_BATCH_SIZE>::deserialize(deserializer)?;
+        let mut batch = ReadingBatch::new();
+        for reading in readings {
+            // Can not fail, the vec holds at most as many readings as a batch
+            let _ = batch.push(reading);
+        }
+        Ok(batch)
+    }
+}
 
-  Returns the amount of bytes used *for* decoding the payload and the decoded value. You
-  need to manually add the amount of bytes used for the prefix to the final result
+message_struct! {
+    /**
+      A reading sent as the time since the previous reading. See `delta::ReadingEncoder`
+    */
+    #[derive(Debug, PartialEq, Clone)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct CompactReading {
+        pub state: State,
+        pub delta: VarInt,
+        pub changed: ChannelMask,
+    }
+}
+
+message_struct! {
+    /**
+      `count` readings starting with `first` and spaced `period` ticks apart.
+      If `toggles` is set every reading after the first toggles the channels in
+      `first.changed`, otherwise they all have the state of `first`, which is
+      the case for clocks where only one edge is recorded. Describes periodic
+      signals like clocks without sending every edge. See `run::RunEncoder`
+    */
+    #[derive(Debug, PartialEq, Clone)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct ReadingRun {
+        pub first: Reading,
+        pub period: VarInt,
+        pub count: VarInt,
+        pub toggles: bool,
+    }
+}
+
+message_struct! {
+    /**
+      Samples every `period` ticks until `blocks` blocks have been sent, or
+      until sampling is stopped if `blocks` is 0. See `sampling`
+    */
+    #[derive(Debug, PartialEq, Clone)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct SampleConfig {
+        pub period: VarInt,
+        pub blocks: VarInt,
+    }
+}
+
+message_struct! {
+    /**
+      Raw samples of the GPIO input register taken `period` ticks apart, the
+      first one at `start_time`. `DeviceInfo::channel_lines` tells which bits
+      hold the channels
+    */
+    #[derive(Debug, PartialEq, Clone)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct SampleBlock {
+        pub start_time: u32,
+        pub period: VarInt,
+        pub samples: Vec<u16, SAMPLE_BLOCK_SIZE>,
+    }
+}
+
+/**
+  An unsigned integer encoded as a LEB128 varint: 7 bits per byte, least
+  significant group first, with the high bit set on every byte but the last
 */
-macro_rules! decode_enum_variants {
-    (
-        $prefix:expr, $bytes:expr, $enum_name:ident 
-        {
-            $( $prefix_value:expr => ($variant:ident, $inner_type:ident) ),* 
-        }
-    ) => {
-        match $prefix {
-            $(
-                $prefix_value => {
-                    let (len, val) = $inner_type::decode($bytes)?;
-                    Ok((len, $enum_name::$variant(val)))
-                }
-            )*,
-            byte => Err(DecodingError::UnexpectedByte(byte, "unexpected prefix for $enum_name"))
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct VarInt(pub u32);
+
+/// The amount of bytes needed to encode any `u32` as a `VarInt`
+const VARINT_MAX_BYTES: usize = 5;
+
+message_struct! {
+    /**
+      Readings were dropped because the ring buffer on the device was full
+    */
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct Overflow {
+        pub dropped: u32, // Amount of dropped readings
+        pub at_time: u32, // Time of the first dropped reading
+    }
+}
+
+message_struct! {
+    #[derive(Debug, PartialEq, Clone)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct Version {
+        pub major: u8,
+        pub minor: u8,
+        pub patch: u8,
+    }
+}
+
+message_struct! {
+    /**
+      Capabilities of the device, sent when it starts and in response to
+      `HostClientMessage::RequestInfo`
+    */
+    #[derive(Debug, PartialEq, Clone)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct DeviceInfo {
+        pub protocol_version: u8,
+        pub firmware_version: Version,
+        pub channel_count: u8,
+        pub timer_frequency: u32, // Hertz
+        pub buffer_size: u32, // Amount of readings that fit in the ring buffer
+        // The bit of a raw sample that holds each channel. Holds at most
+        // `MAX_CHANNELS` entries
+        pub channel_lines: Vec<u8, 32>,
+    }
+}
+
+impl DeviceInfo {
+    /**
+      A device speaking the current protocol with `channel_count` channels on
+      consecutive lines. Shared by the tests of this crate and of the host
+    */
+    #[cfg(any(test, feature = "std"))]
+    pub fn for_test(channel_count: u8) -> Self {
+        DeviceInfo {
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: Version {major: 0, minor: 1, patch: 2},
+            channel_count,
+            timer_frequency: 72_000_000,
+            buffer_size: 199,
+            channel_lines: (0..channel_count).collect(),
         }
     }
 }
 
+message_enum! {
+    /**
+      What went wrong on the device
+    */
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub enum ErrorCode {
+        1 => UartWrite,
+        2 => UartRead,
+        3 => Encode, // `detail` is the tag of the message that could not be encoded
+        4 => UnknownCommand, // `detail` is the unexpected byte
+        5 => CorruptCommand, // `detail` is the amount of dropped bytes
+        6 => NoSuchChannel, // `detail` is the requested channel
+        7 => InvalidSamplePeriod, // `detail` is the requested period
+        8 => SampleBurstTooLong, // `detail` is the most blocks a burst can have
+        9 => InvalidArgument, // `detail` is the rejected value
+    }
+}
+
+message_struct! {
+    /**
+      A recoverable error on the device. The meaning of `detail` depends on `code`
+    */
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct DeviceError {
+        pub code: ErrorCode,
+        pub detail: u32,
+    }
+}
+
+message_struct! {
+    /**
+      The answer to `HostClientMessage::Ping`. `device_ticks` is the time at
+      which the ping was received
+    */
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct Pong {
+        pub nonce: u32,
+        pub device_ticks: u32,
+    }
+}
+
+message_enum! {
+    /**
+      Messages sent from the device to the host, always inside a `ClientHostFrame`
+    */
+    #[derive(Debug, PartialEq, Clone)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    // There is no allocator on the device to box the batch with
+    #[allow(clippy::large_enum_variant)]
+    pub enum ClientHostMessage {
+        1 => Reading(Reading),
+        2 => FrequencyHertz(u32),
+        3 => Reset(u8), // Reset the specified channel readings
+        4 => CurrentTime(u32),
+        5 => DeviceInfo(DeviceInfo),
+        6 => CompactReading(CompactReading),
+        7 => Readings(ReadingBatch),
+        8 => Overflow(Overflow),
+        9 => Error(DeviceError),
+        10 => Pong(Pong),
+        11 => Run(ReadingRun),
+        12 => Triggered(CaptureWindow),
+        13 => Samples(SampleBlock),
+    }
+}
+
+/**
+  A frame sent by the device. `sequence` is increased by one for every frame and
+  wraps around, which lets the host detect lost and duplicated frames. See
+  `sequence::SequenceTracker`
+*/
+#[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ClientHostFrame {
+    pub sequence: u8,
+    pub message: ClientHostMessage,
+}
+
+message_enum! {
+    /**
+      Which edges of a channel should generate readings
+    */
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub enum EdgeMode {
+        1 => Rising,
+        2 => Falling,
+        3 => Both,
+    }
+}
+
+message_struct! {
+    #[derive(Debug, PartialEq, Clone)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct ChannelEdgeMode {
+        pub channel: u8,
+        pub mode: EdgeMode,
+    }
+}
+
+message_struct! {
+    /**
+      Matches states where the channels in `mask` have the values they have in
+      `value`
+    */
+    #[derive(Debug, PartialEq, Clone)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct Pattern {
+        pub mask: ChannelMask,
+        pub value: ChannelMask,
+    }
+}
+
+message_enum! {
+    /**
+      The condition that starts a capture. See `trigger::TriggerEvaluator`
+    */
+    #[derive(Debug, PartialEq, Clone)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub enum Trigger {
+        1 => Edge(ChannelEdgeMode),
+        2 => Pattern(Pattern), // Fires when the channels start matching the pattern
+    }
+}
+
+message_struct! {
+    /**
+      Captures `pre_trigger` readings before the reading that fires `trigger`
+      and `post_trigger` readings after it. See `trigger::Capture`
+    */
+    #[derive(Debug, PartialEq, Clone)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct CaptureConfig {
+        pub trigger: Trigger,
+        pub pre_trigger: VarInt,
+        pub post_trigger: VarInt,
+    }
+}
+
+message_struct! {
+    /**
+      Sent when a capture is complete, followed by the `pre_trigger + 1 +
+      post_trigger` readings of the capture
+    */
+    #[derive(Debug, PartialEq, Clone)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct CaptureWindow {
+        pub trigger_time: u32,
+        pub pre_trigger: VarInt,
+        pub post_trigger: VarInt,
+    }
+}
+
+frame_enum! {
+    /**
+      Commands sent from the host to the device
+    */
+    #[derive(Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub enum HostClientMessage {
+        1 => Start,
+        2 => Stop,
+        3 => EnableChannel(u8),
+        4 => SetEdgeMode(ChannelEdgeMode),
+        5 => RequestInfo,
+        // Time between `CurrentTime` messages in milliseconds, from 1 to
+        // `MAX_HEARTBEAT_MILLIS`
+        6 => SetHeartbeatRate(u32),
+        7 => Ping(u32), // Answered with a `Pong` carrying the same nonce
+        8 => DisableChannel(u8),
+        // Readings are kept on the device until the trigger fires, then the
+        // capture is sent
+        9 => ArmTrigger(CaptureConfig),
+        10 => DisarmTrigger,
+        // Samples at a fixed rate instead of recording edges until `StopSampling`
+        11 => StartSampling(SampleConfig),
+        12 => StopSampling,
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//                 Encoding and decoding functions
+////////////////////////////////////////////////////////////////////////////////
+
 pub trait Message<S> {
+    /// The largest amount of bytes `encode` can produce, a buffer of this size
+    /// fits any value
+    const MAX_ENCODED_LEN: usize;
+
     fn encode(&self, buffer: &mut [u8]) -> Result<usize, EncodingError>;
-    fn decode(bytes: &[u8]) -> Result<(usize, S), DecodingError>;
+    fn decode(bytes: &[u8], context: DecodingContext) -> Result<(usize, S), DecodingError>;
+}
+
+/**
+  What the receiver knows about the sender from the `DeviceInfo` handshake,
+  which is needed to decode messages that are not encoded in full.
+
+  The default context knows no channels, states can only be decoded once the
+  device info has been received.
+*/
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct DecodingContext {
+    /// The width of every `State`
+    pub channel_count: u8,
 }
 
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum EncodingError {
     BufferToSmall,
 }
 #[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum DecodingError {
     EndOfBytes,
     UnexpectedByte(u8, &'static str),
     IncorrectPrefixByte(u8),
+    /// The checksum of a frame did not match its content. Contains the received
+    /// and the calculated checksum
+    ChecksumMismatch(u8, u8),
+    /// A frame with a known end could not be decoded
+    MalformedFrame,
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for EncodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodingError::BufferToSmall => write!(f, "Buffer too small for encoded message"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for DecodingError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodingError::EndOfBytes => write!(f, "Message ended unexpectedly"),
+            DecodingError::UnexpectedByte(byte, message) => {
+                write!(f, "Unexpected byte {:#04x}: {}", byte, message)
+            }
+            DecodingError::IncorrectPrefixByte(byte) => write!(
+                f,
+                "Expected frame prefix {:#04x} but got {:#04x}",
+                MESSAGE_PREFIX,
+                byte
+            ),
+            DecodingError::ChecksumMismatch(received, calculated) => write!(
+                f,
+                "Received checksum {:#04x} but calculated {:#04x}",
+                received,
+                calculated
+            ),
+            DecodingError::MalformedFrame => write!(f, "Malformed frame"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for EncodingError {}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodingError {}
+
+/**
+  Appends a checksum of the first `len` bytes of `buff`, returning the new length
+*/
+fn append_checksum(buff: &mut [u8], len: usize) -> Result<usize, EncodingError> {
+    if buff.len() < len + 1 {
+        return Err(EncodingError::BufferToSmall);
+    }
+    buff[len] = crc8(&buff[..len]);
+    Ok(len + 1)
+}
+
+/**
+  Checks that the byte after the first `len` bytes of `bytes` is their checksum,
+  returning the length including the checksum
+*/
+fn verify_checksum(bytes: &[u8], len: usize) -> Result<usize, DecodingError> {
+    let received = *bytes.get(len).ok_or(DecodingError::EndOfBytes)?;
+    let calculated = crc8(&bytes[..len]);
+    if received != calculated {
+        return Err(DecodingError::ChecksumMismatch(received, calculated));
+    }
+    Ok(len + 1)
+}
+
+/**
+  Enums declared with `message_enum!` or `frame_enum!`, where each variant is
+  identified by a tag byte and carries an optional payload
+*/
+pub trait Tagged: Sized {
+    /// The largest payload of any variant
+    const MAX_PAYLOAD_LEN: usize;
+
+    fn tag(&self) -> u8;
+    fn encode_payload(&self, buffer: &mut [u8]) -> Result<usize, EncodingError>;
+    fn decode_payload(tag: u8, bytes: &[u8], context: DecodingContext)
+        -> Result<(usize, Self), DecodingError>;
+}
+
+/// Bytes added to the payload by `encode_tagged`: the tag
+pub const TAG_OVERHEAD: usize = 1;
+/// Bytes added to the payload by `encode_frame`: the prefix, the tag and the checksum
+pub const FRAME_OVERHEAD: usize = 3;
+
+/**
+  Returns the largest of `lens`. Used to compute `MAX_ENCODED_LEN` of enums
+*/
+pub const fn max_len(lens: &[usize]) -> usize {
+    let mut max = 0;
+    let mut i = 0;
+    while i < lens.len() {
+        if lens[i] > max {
+            max = lens[i];
+        }
+        i += 1;
+    }
+    max
+}
+
+/**
+  Encodes the tag of a `Tagged` value followed by its payload
+*/
+pub fn encode_tagged<T: Tagged>(value: &T, buff: &mut [u8]) -> Result<usize, EncodingError> {
+    let used_bytes = value.tag().encode(buff)?;
+    Ok(used_bytes + value.encode_payload(&mut buff[used_bytes..])?)
+}
+
+pub fn decode_tagged<T: Tagged>(bytes: &[u8], context: DecodingContext)
+    -> Result<(usize, T), DecodingError>
+{
+    let (used_bytes, tag) = u8::decode(bytes, context)?;
+    let (len, val) = T::decode_payload(tag, &bytes[used_bytes..], context)?;
+    Ok((used_bytes + len, val))
+}
+
+/**
+  Writes a frame: the message prefix, the content written by `encode_content`
+  and finally a checksum of everything before it
+*/
+fn encode_frame_with<F>(buff: &mut [u8], encode_content: F) -> Result<usize, EncodingError>
+    where F: FnOnce(&mut [u8]) -> Result<usize, EncodingError>
+{
+    if buff.is_empty() {
+        return Err(EncodingError::BufferToSmall);
+    }
+
+    buff[0] = MESSAGE_PREFIX;
+    let used_bytes = encode_content(&mut buff[1..])?;
+
+    append_checksum(buff, used_bytes + 1)
+}
+
+fn decode_frame_with<T, F>(bytes: &[u8], decode_content: F) -> Result<(usize, T), DecodingError>
+    where F: FnOnce(&[u8]) -> Result<(usize, T), DecodingError>
+{
+    if bytes.len() < 2 {
+        return Err(DecodingError::EndOfBytes);
+    }
+
+    if bytes[0] != MESSAGE_PREFIX {
+        return Err(DecodingError::IncorrectPrefixByte(bytes[0]));
+    }
+
+    let (len, val) = decode_content(&bytes[1..])?;
+
+    Ok((verify_checksum(bytes, len + 1)?, val))
+}
+
+/**
+  Encodes a `Tagged` value in a frame: the message prefix, the tag, the payload
+  and finally a checksum of everything before it
+*/
+pub fn encode_frame<T: Tagged>(value: &T, buff: &mut [u8]) -> Result<usize, EncodingError> {
+    encode_frame_with(buff, |buff| encode_tagged(value, buff))
+}
+
+pub fn decode_frame<T: Tagged>(bytes: &[u8], context: DecodingContext)
+    -> Result<(usize, T), DecodingError>
+{
+    decode_frame_with(bytes, |bytes| decode_tagged(bytes, context))
+}
+
+/**
+  Returns the offset of the next frame prefix after the first byte of `bytes`
+*/
+pub fn next_frame_start(bytes: &[u8]) -> usize {
+    bytes.iter()
+        .skip(1)
+        .position(|b| *b == MESSAGE_PREFIX)
+        .map(|pos| pos + 1)
+        .unwrap_or(bytes.len())
+}
+
+////////////////////////////////////////////////////////////////////////////////
+//                 Stream decoding
+////////////////////////////////////////////////////////////////////////////////
+
+/**
+  Messages that can be decoded from a stream of bytes where frames can be corrupt
+*/
+pub trait Resync {
+    /**
+      Returns the amount of bytes to drop from the start of `bytes`, which
+      starts with a frame that could not be decoded, to get to the next frame
+    */
+    fn resync_offset(bytes: &[u8]) -> usize;
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StreamEvent<M> {
+    Message(M),
+    Resync(StreamError),
+}
+
+/**
+  `skipped` bytes starting at `offset` in the stream were dropped because they
+  could not be decoded
+*/
+#[derive(Debug, PartialEq)]
+pub struct StreamError {
+    pub offset: usize,
+    pub skipped: usize,
+    pub error: DecodingError,
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Dropped {} bytes at byte offset {}: {}",
+            self.skipped,
+            self.offset,
+            self.error
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for StreamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/**
+  Incremental decoder for a stream of frames, usable without an allocator.
+
+  Bytes are pushed as they are received and complete messages are pulled out.
+  After a failed decode, the decoder skips directly to the next possible frame
+  start. An incomplete frame is decoded again from its start once new bytes
+  have arrived, since the end of a frame is only known by decoding it. Pushing
+  a frame a byte at a time thus takes time quadratic in its length, push bytes
+  in chunks where that matters. Frames that do not fit in the `N` byte buffer
+  are dropped.
+*/
+pub struct StreamDecoder<M, const N: usize> {
+    buffer: [u8; N],
+    start: usize,
+    end: usize,
+    // Stream position of `buffer[start]`
+    offset: usize,
+    // Set when the buffered bytes are an incomplete frame
+    waiting_for_bytes: bool,
+    context: DecodingContext,
+    _message: PhantomData<M>,
+}
+
+impl<M: Message<M> + Resync, const N: usize> StreamDecoder<M, N> {
+    pub fn new() -> Self {
+        Self {
+            buffer: [0; N],
+            start: 0,
+            end: 0,
+            offset: 0,
+            waiting_for_bytes: false,
+            context: DecodingContext::default(),
+            _message: PhantomData,
+        }
+    }
+
+    /// Decodes the following messages with `context`
+    pub fn set_context(&mut self, context: DecodingContext) {
+        self.context = context;
+    }
+
+    /**
+      Adds as many of `bytes` to the buffer as fit, returning how many were
+      added. Call `pull` until it returns `None` to make room for the rest
+    */
+    pub fn push(&mut self, bytes: &[u8]) -> usize {
+        if self.end + bytes.len() > N && self.start > 0 {
+            self.buffer.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+
+        let amount = bytes.len().min(N - self.end);
+        self.buffer[self.end..self.end + amount].copy_from_slice(&bytes[..amount]);
+        self.end += amount;
+        if amount > 0 {
+            self.waiting_for_bytes = false;
+        }
+        amount
+    }
+
+    /// Returns the next message or resync event, or `None` if more bytes are needed
+    pub fn pull(&mut self) -> Option<StreamEvent<M>> {
+        if self.waiting_for_bytes || self.start == self.end {
+            return None;
+        }
+
+        let bytes = &self.buffer[self.start..self.end];
+        let error = match M::decode(bytes, self.context) {
+            Ok((len, message)) => {
+                self.consume(len);
+                return Some(StreamEvent::Message(message));
+            }
+            Err(DecodingError::EndOfBytes) => {
+                if bytes.len() < N {
+                    self.waiting_for_bytes = true;
+                    return None;
+                }
+                // The frame can never fit in the buffer
+                DecodingError::MalformedFrame
+            }
+            Err(e) => e
+        };
+
+        let offset = self.offset;
+        let skipped = M::resync_offset(bytes).max(1);
+        self.consume(skipped);
+        Some(StreamEvent::Resync(StreamError{offset, skipped, error}))
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.start += amount;
+        self.offset = self.offset.wrapping_add(amount);
+        if self.start == self.end {
+            self.start = 0;
+            self.end = 0;
+        }
+    }
+}
+
+impl<M: Message<M> + Resync, const N: usize> Default for StreamDecoder<M, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/**
+  Frames from the device are encoded like a frame of a `frame_enum!` with the
+  sequence number placed between the prefix and the tag
+*/
+impl Message<Self> for ClientHostFrame {
+    const MAX_ENCODED_LEN: usize = FRAME_OVERHEAD
+        + u8::MAX_ENCODED_LEN
+        + <ClientHostMessage as Tagged>::MAX_PAYLOAD_LEN;
+
+    fn encode(&self, buffer: &mut [u8]) -> Result<usize, EncodingError> {
+        encode_client_host_frame(self.sequence, &self.message, buffer)
+    }
+
+    fn decode(bytes: &[u8], context: DecodingContext) -> Result<(usize, Self), DecodingError> {
+        decode_frame_with(bytes, |bytes| {
+            let (used_bytes, sequence) = u8::decode(bytes, context)?;
+            let (len, message) = ClientHostMessage::decode(&bytes[used_bytes..], context)?;
+            Ok((used_bytes + len, ClientHostFrame {sequence, message}))
+        })
+    }
+}
+
+/**
+  Encodes the frame `ClientHostFrame {sequence, message}` without building it,
+  so that the device does not need a copy of the message
+*/
+pub fn encode_client_host_frame(sequence: u8, message: &ClientHostMessage, buffer: &mut [u8])
+    -> Result<usize, EncodingError>
+{
+    encode_frame_with(buffer, |buffer| {
+        let used_bytes = sequence.encode(buffer)?;
+        Ok(used_bytes + message.encode(&mut buffer[used_bytes..])?)
+    })
 }
 
+impl Resync for ClientHostFrame {
+    fn resync_offset(bytes: &[u8]) -> usize {
+        next_frame_start(bytes)
+    }
+}
+
+/**
+  States are encoded as as many bytes of the channel word as are needed to hold
+  the channel count of the device, least significant first
+*/
 impl Message<Self> for State {
+    const MAX_ENCODED_LEN: usize = (MAX_CHANNELS as usize).div_ceil(8);
+
     fn encode(&self, buff: &mut [u8]) -> Result<usize, EncodingError> {
-        if buff.len() < 1 {
+        let data_bytes = State::data_bytes(self.channel_count);
+        if buff.len() < data_bytes {
             return Err(EncodingError::BufferToSmall);
         }
-        buff[0] = self.data;
-        Ok(1)
+        for (i, byte) in buff[..data_bytes].iter_mut().enumerate() {
+            *byte = (self.data >> (i * 8)) as u8;
+        }
+        Ok(data_bytes)
     }
-    fn decode(bytes: &[u8]) -> Result<(usize, Self), DecodingError> {
-        let byte = *bytes.get(0).ok_or(DecodingError::EndOfBytes)?;
-        if byte > 0b11 {
-            return Err(DecodingError::UnexpectedByte(byte, "State byte must be > 0b11"));
+    fn decode(bytes: &[u8], context: DecodingContext) -> Result<(usize, Self), DecodingError> {
+        let channel_count = context.channel_count;
+        if channel_count > MAX_CHANNELS {
+            return Err(DecodingError::UnexpectedByte(
+                channel_count,
+                "State channel count must be <= 32"
+            ));
+        }
+        let data_bytes = State::data_bytes(channel_count);
+        if bytes.len() < data_bytes {
+            return Err(DecodingError::EndOfBytes);
+        }
+        let data = bytes[..data_bytes].iter()
+            .enumerate()
+            .fold(0, |acc, (i, byte)| acc | (*byte as u32) << (i * 8));
+
+        if data & !State::channel_mask(channel_count) != 0 {
+            return Err(DecodingError::UnexpectedByte(
+                bytes[data_bytes - 1],
+                "State has values for channels above the channel count"
+            ));
         }
-        Ok((1, Self{data: byte}))
+        Ok((data_bytes, Self{data, channel_count}))
     }
 }
 
-impl Message<Self> for Reading {
+impl Message<Self> for ReadingBatch {
+    const MAX_ENCODED_LEN: usize = u8::MAX_ENCODED_LEN
+        + Reading::MAX_ENCODED_LEN
+        + (MAX_BATCH_SIZE - 1) * CompactReading::MAX_ENCODED_LEN;
+
     fn encode(&self, buffer: &mut [u8]) -> Result<usize, EncodingError> {
-        let used_bytes = self.state.encode(buffer)?;
-        Ok(used_bytes + self.time.encode(&mut buffer[used_bytes..])?)
+        let mut used_bytes = self.len.encode(buffer)?;
+
+        let mut previous_time = None;
+        for reading in self.readings() {
+            used_bytes += match previous_time {
+                None => reading.encode(&mut buffer[used_bytes..])?,
+                Some(previous) => CompactReading {
+                    state: reading.state,
+                    delta: VarInt(reading.time.wrapping_sub(previous)),
+                    changed: reading.changed,
+                }.encode(&mut buffer[used_bytes..])?
+            };
+            previous_time = Some(reading.time);
+        }
+        Ok(used_bytes)
     }
 
-    fn decode(bytes: &[u8]) -> Result<(usize, Self), DecodingError> {
-        let (used_bytes_state, state) = State::decode(bytes)?;
-        let (used_bytes_time, time) = u32::decode(&bytes[used_bytes_state..])?;
+    fn decode(bytes: &[u8], context: DecodingContext) -> Result<(usize, Self), DecodingError> {
+        let (mut used_bytes, len) = u8::decode(bytes, context)?;
+        if len as usize > MAX_BATCH_SIZE {
+            return Err(DecodingError::UnexpectedByte(len, "Too many readings in batch"));
+        }
 
-        Ok((used_bytes_state + used_bytes_time, Reading{state, time}))
+        let mut batch = ReadingBatch::new();
+        for i in 0..len {
+            let reading = if i == 0 {
+                let (len, reading) = Reading::decode(&bytes[used_bytes..], context)?;
+                used_bytes += len;
+                reading
+            }
+            else {
+                let (len, compact) = CompactReading::decode(&bytes[used_bytes..], context)?;
+                used_bytes += len;
+                let previous = batch.readings[i as usize - 1].time;
+                Reading {
+                    state: compact.state,
+                    time: previous.wrapping_add(compact.delta.0),
+                    changed: compact.changed,
+                }
+            };
+            // The length is checked above so the batch can not be full
+            let _ = batch.push(reading);
+        }
+        Ok((used_bytes, batch))
     }
 }
 
-impl Message<Self> for ClientHostMessage {
-    fn encode(&self, buff: &mut [u8]) -> Result<usize, EncodingError> {
-        if buff.len() < 2 {
-            return Err(EncodingError::BufferToSmall);
+/**
+  Implements `Message` for integer types by encoding them as little endian
+*/
+macro_rules! little_endian_message {
+    ($($type:ty),*) => {
+        $(
+            impl Message<Self> for $type {
+                const MAX_ENCODED_LEN: usize = ::core::mem::size_of::<$type>();
+
+                fn encode(&self, buff: &mut [u8]) -> Result<usize, EncodingError> {
+                    let bytes = self.to_le_bytes();
+                    if buff.len() < bytes.len() {
+                        return Err(EncodingError::BufferToSmall);
+                    }
+                    buff[..bytes.len()].copy_from_slice(&bytes);
+                    Ok(bytes.len())
+                }
+
+                fn decode(bytes: &[u8], _context: DecodingContext)
+                    -> Result<(usize, Self), DecodingError>
+                {
+                    let len = ::core::mem::size_of::<$type>();
+                    if bytes.len() < len {
+                        return Err(DecodingError::EndOfBytes);
+                    }
+                    let mut le_bytes = [0; ::core::mem::size_of::<$type>()];
+                    le_bytes.copy_from_slice(&bytes[..len]);
+                    Ok((len, <$type>::from_le_bytes(le_bytes)))
+                }
+            }
+        )*
+    }
+}
+
+little_endian_message!(u16, u32, u64, i32);
+
+impl VarInt {
+    /// The amount of bytes `value` takes up when encoded as a `VarInt`
+    pub const fn encoded_len(value: u32) -> usize {
+        let mut len = 1;
+        let mut value = value >> 7;
+        while value != 0 {
+            len += 1;
+            value >>= 7;
         }
+        len
+    }
+}
 
-        buff[0] = MESSAGE_PREFIX;
+impl Message<Self> for ChannelMask {
+    const MAX_ENCODED_LEN: usize = VarInt::MAX_ENCODED_LEN;
 
-        buff[1] = match *self {
-            ClientHostMessage::Reading(_) => 1,
-            ClientHostMessage::FrequencyHertz(_) => 2,
-            ClientHostMessage::Reset(_) => 3,
-            ClientHostMessage::CurrentTime(_) => 4,
-        };
+    fn encode(&self, buff: &mut [u8]) -> Result<usize, EncodingError> {
+        VarInt(self.0).encode(buff)
+    }
 
-        let remainder = &mut buff[2..];
+    fn decode(bytes: &[u8], context: DecodingContext) -> Result<(usize, Self), DecodingError> {
+        VarInt::decode(bytes, context).map(|(len, VarInt(mask))| (len, ChannelMask(mask)))
+    }
+}
 
-        let used_bytes = match *self {
-            ClientHostMessage::Reading(ref val) => val.encode(remainder)?,
-            ClientHostMessage::FrequencyHertz(ref val) => val.encode(remainder)?,
-            ClientHostMessage::Reset(ref val) => val.encode(remainder)?,
-            ClientHostMessage::CurrentTime(ref val) => val.encode(remainder)?,
-        };
+impl Message<Self> for VarInt {
+    const MAX_ENCODED_LEN: usize = VARINT_MAX_BYTES;
+
+    fn encode(&self, buff: &mut [u8]) -> Result<usize, EncodingError> {
+        let mut value = self.0;
+        let mut used_bytes = 0;
+        loop {
+            if used_bytes >= buff.len() {
+                return Err(EncodingError::BufferToSmall);
+            }
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                buff[used_bytes] = byte;
+                return Ok(used_bytes + 1);
+            }
+            buff[used_bytes] = byte | 0x80;
+            used_bytes += 1;
+        }
+    }
 
-        Ok(used_bytes + 2)
+    fn decode(bytes: &[u8], _context: DecodingContext) -> Result<(usize, Self), DecodingError> {
+        let mut result = 0u32;
+        for (i, byte) in bytes.iter().enumerate() {
+            // The fifth byte may only hold the 4 remaining bits of a u32
+            if i == VARINT_MAX_BYTES - 1 && *byte > 0x0f {
+                return Err(DecodingError::UnexpectedByte(*byte, "VarInt does not fit in a u32"));
+            }
+            result |= ((byte & 0x7f) as u32) << (i * 7);
+            if byte & 0x80 == 0 {
+                return Ok((i + 1, VarInt(result)));
+            }
+        }
+        Err(DecodingError::EndOfBytes)
     }
+}
+
+impl Message<Self> for u8 {
+    const MAX_ENCODED_LEN: usize = 1;
 
-    fn decode(bytes: &[u8]) -> Result<(usize, Self), DecodingError> {
-        if bytes.len() < 2 {
+    fn encode(&self, buff: &mut [u8]) -> Result<usize, EncodingError> {
+        if buff.is_empty() {
+            return Err(EncodingError::BufferToSmall);
+        }
+        buff[0] = *self;
+        Ok(1)
+    }
+
+    fn decode(bytes: &[u8], _context: DecodingContext) -> Result<(usize, Self), DecodingError> {
+        if bytes.is_empty() {
             return Err(DecodingError::EndOfBytes);
         }
+        Ok((1, bytes[0]))
+    }
+}
 
-        if bytes[0] != MESSAGE_PREFIX {
-            return Err(DecodingError::IncorrectPrefixByte(bytes[0]));
+impl Message<Self> for bool {
+    const MAX_ENCODED_LEN: usize = 1;
+
+    fn encode(&self, buff: &mut [u8]) -> Result<usize, EncodingError> {
+        (*self as u8).encode(buff)
+    }
+
+    fn decode(bytes: &[u8], context: DecodingContext) -> Result<(usize, Self), DecodingError> {
+        match u8::decode(bytes, context)? {
+            (len, 0) => Ok((len, false)),
+            (len, 1) => Ok((len, true)),
+            (_, byte) => Err(DecodingError::UnexpectedByte(byte, "bool must be 0 or 1"))
         }
+    }
+}
+
+impl<T: Message<T> + Copy + Default, const N: usize> Message<Self> for [T; N] {
+    const MAX_ENCODED_LEN: usize = N * T::MAX_ENCODED_LEN;
 
-        let (len, val) = decode_enum_variants!{bytes[1], &bytes[2..], ClientHostMessage {
-            1 => (Reading, Reading),
-            2 => (FrequencyHertz, u32),
-            3 => (Reset, u8),
-            4 => (CurrentTime, u32)
-        }}?;
+    fn encode(&self, buff: &mut [u8]) -> Result<usize, EncodingError> {
+        let mut used_bytes = 0;
+        for val in self {
+            used_bytes += val.encode(&mut buff[used_bytes..])?;
+        }
+        Ok(used_bytes)
+    }
 
-        Ok((len + 2, val))
+    fn decode(bytes: &[u8], context: DecodingContext) -> Result<(usize, Self), DecodingError> {
+        let mut result = [T::default(); N];
+        let mut used_bytes = 0;
+        for val in result.iter_mut() {
+            let (len, decoded) = T::decode(&bytes[used_bytes..], context)?;
+            *val = decoded;
+            used_bytes += len;
+        }
+        Ok((used_bytes, result))
     }
 }
 
-impl Message<Self> for u32 {
+/**
+  Options are encoded as a 0 for `None` or a 1 followed by the value for `Some`
+*/
+impl<T: Message<T>> Message<Self> for Option<T> {
+    const MAX_ENCODED_LEN: usize = bool::MAX_ENCODED_LEN + T::MAX_ENCODED_LEN;
+
     fn encode(&self, buff: &mut [u8]) -> Result<usize, EncodingError> {
-        if buff.len() < 4 {
-            return Err(EncodingError::BufferToSmall);
+        let used_bytes = self.is_some().encode(buff)?;
+        match *self {
+            Some(ref val) => Ok(used_bytes + val.encode(&mut buff[used_bytes..])?),
+            None => Ok(used_bytes)
+        }
+    }
+
+    fn decode(bytes: &[u8], context: DecodingContext) -> Result<(usize, Self), DecodingError> {
+        let (used_bytes, is_some) = bool::decode(bytes, context)?;
+        if is_some {
+            let (len, val) = T::decode(&bytes[used_bytes..], context)?;
+            Ok((used_bytes + len, Some(val)))
+        }
+        else {
+            Ok((used_bytes, None))
+        }
+    }
+}
+
+/**
+  Vectors are encoded as their length as a `VarInt` followed by the elements
+*/
+impl<T: Message<T>, const N: usize> Message<Self> for Vec<T, N> {
+    const MAX_ENCODED_LEN: usize = VarInt::encoded_len(N as u32) + N * T::MAX_ENCODED_LEN;
+
+    fn encode(&self, buff: &mut [u8]) -> Result<usize, EncodingError> {
+        let mut used_bytes = VarInt(self.len() as u32).encode(buff)?;
+        for val in self {
+            used_bytes += val.encode(&mut buff[used_bytes..])?;
         }
-        buff[0] = *self as u8;
-        buff[1] = (*self >> 8) as u8;
-        buff[2] = (*self >> 16) as u8;
-        buff[3] = (*self >> 24) as u8;
-        Ok(4)
+        Ok(used_bytes)
     }
 
-    fn decode(bytes: &[u8]) -> Result<(usize, Self), DecodingError> {
-        if bytes.len() < 4 {
-            return Err(DecodingError::EndOfBytes);
+    fn decode(bytes: &[u8], context: DecodingContext) -> Result<(usize, Self), DecodingError> {
+        let (mut used_bytes, len) = VarInt::decode(bytes, context)?;
+        if len.0 as usize > N {
+            return Err(DecodingError::UnexpectedByte(bytes[0], "Too many elements for Vec"));
+        }
+
+        let mut result = Vec::new();
+        for _ in 0..len.0 {
+            let (len, val) = T::decode(&bytes[used_bytes..], context)?;
+            used_bytes += len;
+            // The length is checked above so there is always room
+            let _ = result.push(val);
         }
-        let mut result = bytes[0] as u32;
-        result |= (bytes[1] as u32) << 8;
-        result |= (bytes[2] as u32) << 16;
-        result |= (bytes[3] as u32) << 24;
-        Ok((4, result))
+        Ok((used_bytes, result))
     }
 }
 
-impl Message<Self> for u8 {
+/**
+  Strings are encoded like a `Vec<u8, N>` of their UTF-8 bytes
+*/
+impl<const N: usize> Message<Self> for String<N> {
+    const MAX_ENCODED_LEN: usize = Vec::<u8, N>::MAX_ENCODED_LEN;
+
     fn encode(&self, buff: &mut [u8]) -> Result<usize, EncodingError> {
-        if buff.len() < 1 {
-            return Err(EncodingError::BufferToSmall);
+        let mut used_bytes = VarInt(self.len() as u32).encode(buff)?;
+        for byte in self.as_bytes() {
+            used_bytes += byte.encode(&mut buff[used_bytes..])?;
         }
-        buff[0] = *self as u8;
-        Ok(1)
+        Ok(used_bytes)
     }
 
-    fn decode(bytes: &[u8]) -> Result<(usize, Self), DecodingError> {
-        if bytes.len() < 1 {
-            return Err(DecodingError::EndOfBytes);
-        }
-        Ok((1, bytes[0]))
+    fn decode(bytes: &[u8], context: DecodingContext) -> Result<(usize, Self), DecodingError> {
+        let (len, vec) = Vec::<u8, N>::decode(bytes, context)?;
+        let string = String::from_utf8(vec)
+            .map_err(|_| DecodingError::UnexpectedByte(bytes[0], "String is not valid UTF-8"))?;
+        Ok((len, string))
     }
 }
 
@@ -213,19 +1270,32 @@ impl Message<Self> for u8 {
 
 
 #[cfg(test)]
+// The failure contains the decoded message which is large for batches
+#[allow(clippy::result_large_err)]
 mod encode_decode_tests {
     use super::*;
 
+    // The channel count of the states in most tests
+    const CONTEXT: DecodingContext = DecodingContext {channel_count: 2};
+
+    fn context(channel_count: u8) -> DecodingContext {
+        DecodingContext {channel_count}
+    }
+
     #[derive(Debug, PartialEq)]
     enum EncodeDecodeFailure<T: ::core::fmt::Debug + PartialEq> {
         NoMatch(T),
         EncodingError(EncodingError),
         DecodingError(DecodingError),
-        UsedWrongAmountOfBytes(usize, usize)
+        UsedWrongAmountOfBytes(usize, usize),
+        LongerThanMaxEncodedLen(usize)
     }
 
     macro_rules! test_encode_decode {
         ($type:ident, $value:expr, $buffer_size:expr) => {
+            test_encode_decode!($type, $value, $buffer_size, CONTEXT)
+        };
+        ($type:ident, $value:expr, $buffer_size:expr, $context:expr) => {
             {
                 // Val is passed to force the inference from assuming the wrong type
                 // TODO: Replace the whole closure with a try/catch block in rust 2018
@@ -235,7 +1305,11 @@ mod encode_decode_tests {
                     let encoded_len = val.encode(&mut buffer)
                         .map_err(|e| EncodeDecodeFailure::EncodingError(e))?;
 
-                    let (decoded_len, decoded) = $type::decode(&buffer[..encoded_len])
+                    if encoded_len > $type::MAX_ENCODED_LEN {
+                        return Err(EncodeDecodeFailure::LongerThanMaxEncodedLen(encoded_len))
+                    }
+
+                    let (decoded_len, decoded) = $type::decode(&buffer[..encoded_len], $context)
                         .map_err(|e| EncodeDecodeFailure::DecodingError(e))?;
 
                     if decoded_len != encoded_len {
@@ -262,53 +1336,685 @@ mod encode_decode_tests {
         assert_eq!(test_encode_decode!(u32, 12345678, 4), Ok(()));
     }
 
+    #[test]
+    fn integer_test() {
+        assert_eq!(test_encode_decode!(u16, 0xbeef, 2), Ok(()));
+        assert_eq!(test_encode_decode!(u64, 0x0123_4567_89ab_cdef, 8), Ok(()));
+        assert_eq!(test_encode_decode!(i32, -123456, 4), Ok(()));
+        assert_eq!(test_encode_decode!(i32, i32::MIN, 4), Ok(()));
+    }
+
+    #[test]
+    fn u32_is_little_endian() {
+        let mut buffer = [0; 4];
+        assert_eq!(0x12345678u32.encode(&mut buffer), Ok(4));
+        assert_eq!(buffer, [0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(u32::decode(&buffer[..3], CONTEXT), Err(DecodingError::EndOfBytes));
+    }
+
+    #[test]
+    fn bool_test() {
+        assert_eq!(test_encode_decode!(bool, true, 1), Ok(()));
+        assert_eq!(test_encode_decode!(bool, false, 1), Ok(()));
+        assert_eq!(
+            bool::decode(&[2], CONTEXT),
+            Err(DecodingError::UnexpectedByte(2, "bool must be 0 or 1"))
+        );
+    }
+
+    #[test]
+    fn composite_test() {
+        type Array = [u16; 3];
+        assert_eq!(test_encode_decode!(Array, [1, 2, 0xffff], 6), Ok(()));
+
+        type OptionU32 = Option<u32>;
+        assert_eq!(test_encode_decode!(OptionU32, Some(1234), 5), Ok(()));
+        assert_eq!(test_encode_decode!(OptionU32, None, 1), Ok(()));
+
+        type Bytes = Vec<u8, 8>;
+        assert_eq!(test_encode_decode!(Bytes, Vec::from_slice(&[1, 2, 3]).unwrap(), 4), Ok(()));
+        assert_eq!(test_encode_decode!(Bytes, Vec::new(), 1), Ok(()));
+
+        type Name = String<16>;
+        let mut name = String::new();
+        name.push_str("monocle").unwrap();
+        assert_eq!(test_encode_decode!(Name, name, 8), Ok(()));
+    }
+
+    #[test]
+    fn too_long_vec_fails() {
+        assert_eq!(
+            Vec::<u8, 2>::decode(&[3, 1, 2, 3], CONTEXT),
+            Err(DecodingError::UnexpectedByte(3, "Too many elements for Vec"))
+        );
+        assert_eq!(
+            String::<4>::decode(&[2, 0xc3, 0x28], CONTEXT),
+            Err(DecodingError::UnexpectedByte(2, "String is not valid UTF-8"))
+        );
+    }
+
     #[test]
     fn reading_test() {
-        assert_eq!(test_encode_decode!(Reading, Reading::new(123412, true, true), 6), Ok(()));
-        assert_eq!(test_encode_decode!(Reading, Reading::new(123412, false, false), 6), Ok(()));
+        let state = State::from_channels(&[true, true]);
+        assert_eq!(test_encode_decode!(Reading, Reading::new(123412, state), 7), Ok(()));
+        let state = State::from_channels(&[false, false]);
+        assert_eq!(test_encode_decode!(Reading, Reading::new(123412, state), 7), Ok(()));
+        let reading = Reading {
+            state,
+            time: 123412,
+            changed: ChannelMask(0b11)
+        };
+        assert_eq!(test_encode_decode!(Reading, reading, 7), Ok(()));
+    }
+
+    #[test]
+    fn channel_mask_contains() {
+        let mask = ChannelMask(0b101);
+        assert!(mask.contains(0));
+        assert!(!mask.contains(1));
+        assert!(mask.contains(2));
+        assert!(!mask.contains(40));
+        assert!(ChannelMask(1 << 31).contains(31));
     }
 
     #[test]
     fn state_test() {
-        assert_eq!(test_encode_decode!(State, State::new(true, true), 1), Ok(()));
-        assert_eq!(test_encode_decode!(State, State::new(false, true), 1), Ok(()));
-        assert_eq!(test_encode_decode!(State, State::new(true, false), 1), Ok(()));
-        assert_eq!(test_encode_decode!(State, State::new(false, false), 1), Ok(()));
+        assert_eq!(test_encode_decode!(State, State::from_channels(&[true, true]), 1), Ok(()));
+        assert_eq!(test_encode_decode!(State, State::from_channels(&[false, true]), 1), Ok(()));
+        assert_eq!(test_encode_decode!(State, State::from_channels(&[true, false]), 1), Ok(()));
+        assert_eq!(test_encode_decode!(State, State::from_channels(&[false, false]), 1), Ok(()));
+        assert_eq!(test_encode_decode!(State, State::new(0, 0), 0, context(0)), Ok(()));
+        assert_eq!(test_encode_decode!(State, State::new(8, 0xa5), 1, context(8)), Ok(()));
+        assert_eq!(test_encode_decode!(State, State::new(13, 0x1abc), 2, context(13)), Ok(()));
+        assert_eq!(test_encode_decode!(State, State::new(32, 0xdeadbeef), 4, context(32)), Ok(()));
+    }
+
+    #[test]
+    fn states_are_encoded_without_their_width() {
+        let mut buffer = [0; 4];
+        assert_eq!(State::new(9, 0x1ff).encode(&mut buffer), Ok(2));
+        assert_eq!(&buffer[..2], &[0xff, 0x01]);
+        assert_eq!(State::decode(&buffer[..2], context(9)), Ok((2, State::new(9, 0x1ff))));
+        assert_eq!(State::decode(&buffer[..1], context(9)), Err(DecodingError::EndOfBytes));
+    }
+
+    #[test]
+    fn state_channels() {
+        let state = State::from_channels(&[true, false, false, true]);
+        assert_eq!(state.channel_count(), 4);
+        assert!(state.channel(0));
+        assert!(!state.channel(1));
+        assert!(state.channel(3));
+        assert!(!state.channel(4));
+        assert_eq!(State::new(2, 0b111).bits(), 0b11);
+    }
+
+    #[test]
+    fn state_with_too_many_channels_fails() {
+        assert_eq!(
+            State::decode(&[0, 0, 0, 0, 0], context(33)),
+            Err(DecodingError::UnexpectedByte(33, "State channel count must be <= 32"))
+        );
+    }
+
+    #[test]
+    fn state_with_bits_above_channel_count_fails() {
+        assert!(State::decode(&[0b100], context(2)).is_err());
+        assert!(State::decode(&[0, 0b100], context(10)).is_err());
     }
 
     #[test]
     fn client_host_message_test() {
-        let reading = Reading::new(123412, false, false);
+        let reading = Reading::new(123412, State::from_channels(&[false, false]));
         assert_eq!(test_encode_decode!(
             ClientHostMessage,
-            ClientHostMessage::Reading(reading.clone()),
-            7
+            ClientHostMessage::Reading(reading),
+            9
         ), Ok(()));
         assert_eq!(test_encode_decode!(
             ClientHostMessage,
             ClientHostMessage::FrequencyHertz(12345),
-            7
+            8
         ), Ok(()));
         assert_eq!(test_encode_decode!(
             ClientHostMessage,
             ClientHostMessage::Reset(5),
-            7
+            8
         ), Ok(()));
         assert_eq!(test_encode_decode!(
             ClientHostMessage,
             ClientHostMessage::CurrentTime(5),
+            8
+        ), Ok(()));
+        assert_eq!(test_encode_decode!(
+            ClientHostMessage,
+            ClientHostMessage::DeviceInfo(DeviceInfo::for_test(2)),
+            22
+        ), Ok(()));
+        assert_eq!(test_encode_decode!(
+            ClientHostMessage,
+            ClientHostMessage::Overflow(Overflow{dropped: 17, at_time: 123456}),
+            11
+        ), Ok(()));
+    }
+
+    #[test]
+    fn varint_test() {
+        assert_eq!(test_encode_decode!(VarInt, VarInt(0), 1), Ok(()));
+        assert_eq!(test_encode_decode!(VarInt, VarInt(127), 1), Ok(()));
+        assert_eq!(test_encode_decode!(VarInt, VarInt(128), 2), Ok(()));
+        assert_eq!(test_encode_decode!(VarInt, VarInt(123456), 3), Ok(()));
+        assert_eq!(test_encode_decode!(VarInt, VarInt(0xffff_ffff), 5), Ok(()));
+    }
+
+    #[test]
+    fn varint_encoding() {
+        let mut buffer = [0; 5];
+        assert_eq!(VarInt(300).encode(&mut buffer), Ok(2));
+        assert_eq!(&buffer[..2], &[0xac, 0x02]);
+        assert_eq!(VarInt(128).encode(&mut buffer[..1]), Err(EncodingError::BufferToSmall));
+    }
+
+    #[test]
+    fn oversized_varint_fails() {
+        assert_eq!(
+            VarInt::decode(&[0xff, 0xff, 0xff, 0xff, 0x1f], CONTEXT),
+            Err(DecodingError::UnexpectedByte(0x1f, "VarInt does not fit in a u32"))
+        );
+        assert_eq!(VarInt::decode(&[0x80, 0x80], CONTEXT), Err(DecodingError::EndOfBytes));
+    }
+
+    #[test]
+    fn compact_reading_test() {
+        let state = State::from_channels(&[true, false]);
+        assert_eq!(test_encode_decode!(
+            ClientHostMessage,
+            ClientHostMessage::CompactReading(CompactReading {
+                state,
+                delta: VarInt(1000),
+                changed: ChannelMask(0b10)
+            }),
+            7
+        ), Ok(()));
+    }
+
+    #[test]
+    fn reading_batch_test() {
+        let mut batch = ReadingBatch::new();
+        assert_eq!(test_encode_decode!(ReadingBatch, batch.clone(), 1), Ok(()));
+
+        for i in 0..MAX_BATCH_SIZE as u32 {
+            let state = State::from_channels(&[i & 1 == 1, i & 2 == 2]);
+            // Start close to the wraparound
+            assert_eq!(batch.push(Reading::new(0xffff_fff0u32.wrapping_add(i * 7), state)), Ok(()));
+        }
+        assert!(batch.is_full());
+        assert_eq!(
+            batch.push(Reading::new(0, State::new(0, 0))),
+            Err(Reading::new(0, State::new(0, 0)))
+        );
+
+        assert_eq!(test_encode_decode!(
+            ClientHostMessage,
+            ClientHostMessage::Readings(batch),
+            100
+        ), Ok(()));
+    }
+
+    /// A batch of 32 channel readings that are as far apart as possible, all changed
+    fn largest_batch() -> ReadingBatch {
+        let mut batch = ReadingBatch::new();
+        for i in 0..MAX_BATCH_SIZE as u32 {
+            let reading = Reading {
+                state: State::new(MAX_CHANNELS, !0),
+                time: i.wrapping_mul(0xffff_ffff),
+                changed: ChannelMask(!0)
+            };
+            batch.push(reading).unwrap();
+        }
+        batch
+    }
+
+    #[test]
+    fn max_encoded_len_is_reached() {
+        let mut buffer = [0; ClientHostFrame::MAX_ENCODED_LEN];
+        let frame = ClientHostFrame {
+            sequence: 0,
+            message: ClientHostMessage::Readings(largest_batch())
+        };
+        assert_eq!(frame.encode(&mut buffer), Ok(ClientHostFrame::MAX_ENCODED_LEN));
+
+        assert_eq!(State::MAX_ENCODED_LEN, 4);
+        assert_eq!(Reading::MAX_ENCODED_LEN, 13);
+        assert_eq!(EdgeMode::MAX_ENCODED_LEN, 1);
+        assert_eq!(HostClientMessage::MAX_ENCODED_LEN, 3 + 21);
+        assert_eq!(Vec::<u32, 200>::MAX_ENCODED_LEN, 2 + 200 * 4);
+    }
+
+    #[test]
+    fn varint_encoded_len() {
+        assert_eq!(VarInt::encoded_len(0), 1);
+        assert_eq!(VarInt::encoded_len(127), 1);
+        assert_eq!(VarInt::encoded_len(128), 2);
+        assert_eq!(VarInt::encoded_len(0xffff_ffff), VARINT_MAX_BYTES);
+    }
+
+    #[test]
+    fn oversized_batch_fails() {
+        assert_eq!(
+            ReadingBatch::decode(&[MAX_BATCH_SIZE as u8 + 1], CONTEXT),
+            Err(DecodingError::UnexpectedByte(MAX_BATCH_SIZE as u8 + 1, "Too many readings in batch"))
+        );
+    }
+
+    #[test]
+    fn edge_mode_test() {
+        assert_eq!(test_encode_decode!(EdgeMode, EdgeMode::Rising, 1), Ok(()));
+        assert_eq!(test_encode_decode!(EdgeMode, EdgeMode::Falling, 1), Ok(()));
+        assert_eq!(test_encode_decode!(EdgeMode, EdgeMode::Both, 1), Ok(()));
+    }
+
+    #[test]
+    fn host_client_message_test() {
+        assert_eq!(test_encode_decode!(
+            HostClientMessage,
+            HostClientMessage::Start,
+            7
+        ), Ok(()));
+        assert_eq!(test_encode_decode!(
+            HostClientMessage,
+            HostClientMessage::Stop,
+            7
+        ), Ok(()));
+        assert_eq!(test_encode_decode!(
+            HostClientMessage,
+            HostClientMessage::EnableChannel(1),
+            7
+        ), Ok(()));
+        assert_eq!(test_encode_decode!(
+            HostClientMessage,
+            HostClientMessage::DisableChannel(1),
+            7
+        ), Ok(()));
+        assert_eq!(test_encode_decode!(
+            HostClientMessage,
+            HostClientMessage::SetEdgeMode(ChannelEdgeMode{channel: 1, mode: EdgeMode::Falling}),
+            7
+        ), Ok(()));
+        assert_eq!(test_encode_decode!(
+            HostClientMessage,
+            HostClientMessage::RequestInfo,
+            7
+        ), Ok(()));
+        assert_eq!(test_encode_decode!(
+            HostClientMessage,
+            HostClientMessage::SetHeartbeatRate(100),
+            7
+        ), Ok(()));
+    }
+
+    #[test]
+    fn ping_pong_test() {
+        assert_eq!(test_encode_decode!(
+            HostClientMessage,
+            HostClientMessage::Ping(0xdead_beef),
             7
         ), Ok(()));
+        assert_eq!(test_encode_decode!(
+            ClientHostMessage,
+            ClientHostMessage::Pong(Pong {nonce: 0xdead_beef, device_ticks: 0x1234_5678}),
+            9
+        ), Ok(()));
+    }
+
+    #[test]
+    fn reading_run_test() {
+        let run = ReadingRun {
+            first: Reading {
+                state: State::from_channels(&[true, false]),
+                time: 123412,
+                changed: ChannelMask(0b01)
+            },
+            period: VarInt(1000),
+            count: VarInt(5000),
+            toggles: true
+        };
+        assert_eq!(test_encode_decode!(ClientHostMessage, ClientHostMessage::Run(run), 13), Ok(()));
+    }
+
+    #[test]
+    fn trigger_test() {
+        let config = CaptureConfig {
+            trigger: Trigger::Edge(ChannelEdgeMode {channel: 3, mode: EdgeMode::Rising}),
+            pre_trigger: VarInt(100),
+            post_trigger: VarInt(200)
+        };
+        assert_eq!(test_encode_decode!(
+            HostClientMessage,
+            HostClientMessage::ArmTrigger(config),
+            24
+        ), Ok(()));
+        let config = CaptureConfig {
+            trigger: Trigger::Pattern(Pattern {mask: ChannelMask(0b101), value: ChannelMask(0b001)}),
+            pre_trigger: VarInt(0),
+            post_trigger: VarInt(1000)
+        };
+        assert_eq!(test_encode_decode!(
+            HostClientMessage,
+            HostClientMessage::ArmTrigger(config),
+            24
+        ), Ok(()));
+        assert_eq!(test_encode_decode!(
+            HostClientMessage,
+            HostClientMessage::DisarmTrigger,
+            24
+        ), Ok(()));
+        let window = CaptureWindow {
+            trigger_time: 0x1234_5678,
+            pre_trigger: VarInt(100),
+            post_trigger: VarInt(200)
+        };
+        assert_eq!(test_encode_decode!(
+            ClientHostMessage,
+            ClientHostMessage::Triggered(window),
+            12
+        ), Ok(()));
+    }
+
+    #[test]
+    fn sampling_test() {
+        let config = SampleConfig {period: VarInt(72), blocks: VarInt(0)};
+        assert_eq!(test_encode_decode!(
+            HostClientMessage,
+            HostClientMessage::StartSampling(config),
+            24
+        ), Ok(()));
+        assert_eq!(test_encode_decode!(
+            HostClientMessage,
+            HostClientMessage::StopSampling,
+            24
+        ), Ok(()));
+
+        let mut samples = Vec::new();
+        for i in 0..SAMPLE_BLOCK_SIZE as u16 {
+            samples.push(i * 0x0101).unwrap();
+        }
+        let block = SampleBlock {start_time: 0x1234_5678, period: VarInt(72), samples};
+        assert_eq!(test_encode_decode!(
+            ClientHostMessage,
+            ClientHostMessage::Samples(block),
+            ClientHostMessage::MAX_ENCODED_LEN
+        ), Ok(()));
+    }
+
+    #[test]
+    fn device_error_test() {
+        let error = DeviceError {code: ErrorCode::UnknownCommand, detail: 0xaa};
+        assert_eq!(test_encode_decode!(
+            ClientHostMessage,
+            ClientHostMessage::Error(error),
+            6
+        ), Ok(()));
+        assert_eq!(
+            ErrorCode::decode(&[10], CONTEXT),
+            Err(DecodingError::UnexpectedByte(10, "ErrorCode tag must be one of 1, 2, 3, 4, 5, 6, 7, 8, 9"))
+        );
+    }
+
+    #[test]
+    fn client_host_frame_test() {
+        assert_eq!(test_encode_decode!(
+            ClientHostFrame,
+            ClientHostFrame {sequence: 0xfe, message: ClientHostMessage::CurrentTime(0x00fe00fe)},
+            9
+        ), Ok(()));
+        assert_eq!(test_encode_decode!(
+            ClientHostFrame,
+            ClientHostFrame {sequence: 3, message: ClientHostMessage::Reset(1)},
+            5
+        ), Ok(()));
     }
 
     #[test]
     fn incorrect_prefix_fails() {
-        let message = [0xfe, 4, 0, 0, 0, 0, 0xfe];
-        let correct = ClientHostMessage::decode(&message);
-        let incorrect = ClientHostMessage::decode(&message[1..]);
+        let message = [0xfe, 6, 0, 0, 0, 0, crc8(&[0xfe, 6, 0, 0, 0, 0]), 0xfe];
+        let correct = HostClientMessage::decode(&message, CONTEXT);
+        let incorrect = HostClientMessage::decode(&message[1..], CONTEXT);
+
+        assert_eq!(correct, Ok((7, HostClientMessage::SetHeartbeatRate(0))));
+
+        assert_eq!(incorrect, Err(DecodingError::IncorrectPrefixByte(6)));
+    }
+
+    #[test]
+    fn unknown_tag_fails() {
+        let message = [0xfe, 0, 0xaa, crc8(&[0xfe, 0, 0xaa])];
+        assert_eq!(
+            ClientHostFrame::decode(&message, CONTEXT),
+            Err(DecodingError::UnexpectedByte(
+                0xaa,
+                "ClientHostMessage tag must be one of 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13"
+            ))
+        );
+        assert_eq!(
+            EdgeMode::decode(&[4], CONTEXT),
+            Err(DecodingError::UnexpectedByte(4, "EdgeMode tag must be one of 1, 2, 3"))
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn errors_describe_what_was_received() {
+        use std::string::ToString;
+
+        assert_eq!(
+            DecodingError::UnexpectedByte(0xaa, "EdgeMode tag must be one of 1, 2, 3").to_string(),
+            "Unexpected byte 0xaa: EdgeMode tag must be one of 1, 2, 3"
+        );
+        assert_eq!(
+            DecodingError::IncorrectPrefixByte(4).to_string(),
+            "Expected frame prefix 0xfe but got 0x04"
+        );
+        let error = StreamError {
+            offset: 12,
+            skipped: 3,
+            error: DecodingError::ChecksumMismatch(1, 2)
+        };
+        assert_eq!(
+            error.to_string(),
+            "Dropped 3 bytes at byte offset 12: Received checksum 0x01 but calculated 0x02"
+        );
+    }
+
+    #[test]
+    fn corrupted_frame_fails_checksum() {
+        let mut buffer = [0; 8];
+        let len = HostClientMessage::SetHeartbeatRate(0x1234).encode(&mut buffer).unwrap();
+        let checksum = buffer[len - 1];
 
-        assert_eq!(correct, Ok((6, ClientHostMessage::CurrentTime(0))));
+        buffer[3] ^= 0x10;
+
+        assert_eq!(
+            HostClientMessage::decode(&buffer[..len], CONTEXT),
+            Err(DecodingError::ChecksumMismatch(checksum, crc8(&buffer[..len - 1])))
+        );
+    }
+
+    #[test]
+    fn missing_checksum_is_end_of_bytes() {
+        let mut buffer = [0; 8];
+        let len = HostClientMessage::SetHeartbeatRate(0x1234).encode(&mut buffer).unwrap();
+
+        assert_eq!(
+            HostClientMessage::decode(&buffer[..len - 1], CONTEXT),
+            Err(DecodingError::EndOfBytes)
+        );
+    }
+}
+
+
+#[cfg(test)]
+mod stream_decoder_tests {
+    use super::*;
+
+    fn encode(message: ClientHostMessage, buffer: &mut [u8]) -> usize {
+        frame(message).encode(buffer).expect("Failed to encode")
+    }
+
+    fn frame(message: ClientHostMessage) -> ClientHostFrame {
+        ClientHostFrame {sequence: 0, message}
+    }
+
+    #[test]
+    fn messages_split_across_pushes() {
+        let mut buffer = [0; 16];
+        let mut decoder = StreamDecoder::<ClientHostFrame, 16>::new();
+
+        let len = encode(ClientHostMessage::CurrentTime(1234), &mut buffer);
+        for byte in &buffer[..len - 1] {
+            assert_eq!(decoder.push(&[*byte]), 1);
+            assert_eq!(decoder.pull(), None);
+        }
+        decoder.push(&buffer[len - 1..len]);
+
+        assert_eq!(decoder.pull(), Some(StreamEvent::Message(frame(ClientHostMessage::CurrentTime(1234)))));
+        assert_eq!(decoder.pull(), None);
+    }
+
+    #[test]
+    fn garbage_is_skipped_up_to_next_prefix() {
+        let mut buffer = [0; 16];
+        let mut decoder = StreamDecoder::<ClientHostFrame, 32>::new();
+
+        decoder.push(&[1, 2, 3]);
+        let len = encode(ClientHostMessage::Reset(1), &mut buffer);
+        decoder.push(&buffer[..len]);
+
+        assert_eq!(decoder.pull(), Some(StreamEvent::Resync(StreamError {
+            offset: 0,
+            skipped: 3,
+            error: DecodingError::IncorrectPrefixByte(1)
+        })));
+        assert_eq!(decoder.pull(), Some(StreamEvent::Message(frame(ClientHostMessage::Reset(1)))));
+    }
+
+    #[test]
+    fn corrupt_frames_are_reported() {
+        let mut buffer = [0; 16];
+        let mut decoder = StreamDecoder::<ClientHostFrame, 32>::new();
+
+        let len = encode(ClientHostMessage::CurrentTime(5), &mut buffer);
+        let checksum = buffer[len - 1];
+        buffer[len - 1] ^= 1;
+        decoder.push(&buffer[..len]);
+        buffer[len - 1] = checksum;
+        decoder.push(&buffer[..len]);
+
+        assert_eq!(decoder.pull(), Some(StreamEvent::Resync(StreamError {
+            offset: 0,
+            skipped: len,
+            error: DecodingError::ChecksumMismatch(checksum ^ 1, checksum)
+        })));
+        assert_eq!(decoder.pull(), Some(StreamEvent::Message(frame(ClientHostMessage::CurrentTime(5)))));
+        assert_eq!(decoder.pull(), None);
+    }
+
+    #[test]
+    fn states_take_their_width_from_the_context() {
+        let mut buffer = [0; 64];
+        let mut decoder = StreamDecoder::<ClientHostFrame, 64>::new();
+
+        let info_len = encode(ClientHostMessage::DeviceInfo(DeviceInfo::for_test(9)), &mut buffer);
+        let reading = Reading::new(1234, State::new(9, 0x155));
+        let len = info_len + encode(ClientHostMessage::Reading(reading), &mut buffer[info_len..]);
+        decoder.push(&buffer[..len]);
+
+        let channel_count = match decoder.pull() {
+            Some(StreamEvent::Message(ClientHostFrame {
+                message: ClientHostMessage::DeviceInfo(info),
+                ..
+            })) => info.channel_count,
+            other => panic!("Expected device info, got {:?}", other)
+        };
+        decoder.set_context(DecodingContext {channel_count});
+        assert_eq!(
+            decoder.pull(),
+            Some(StreamEvent::Message(frame(ClientHostMessage::Reading(reading))))
+        );
+    }
+
+    #[test]
+    fn frames_larger_than_the_buffer_are_dropped() {
+        let mut buffer = [0; 32];
+        let mut decoder = StreamDecoder::<ClientHostFrame, 8>::new();
+
+        let info = ClientHostMessage::DeviceInfo(DeviceInfo::for_test(2));
+        let info_len = encode(info, &mut buffer);
+        let len = info_len + encode(ClientHostMessage::Reset(2), &mut buffer[info_len..]);
+
+        let mut pending = &buffer[..len];
+        let mut events = Vec::<_, 16>::new();
+        while !pending.is_empty() {
+            let pushed = decoder.push(pending);
+            pending = &pending[pushed..];
+            while let Some(event) = decoder.pull() {
+                events.push(event).unwrap();
+            }
+        }
+
+        assert_eq!(events.first(), Some(&StreamEvent::Resync(StreamError {
+            offset: 0,
+            skipped: 8,
+            error: DecodingError::MalformedFrame
+        })));
+        assert_eq!(events.last(), Some(&StreamEvent::Message(frame(ClientHostMessage::Reset(2)))));
+    }
+}
+
+
+#[cfg(all(test, feature = "serde"))]
+mod serde_tests {
+    use super::*;
+    use serde_json;
+
+    fn reading(time: u32) -> Reading {
+        Reading {
+            state: State::new(4, time & 0b1111),
+            time,
+            changed: ChannelMask(0b1)
+        }
+    }
+
+    #[test]
+    fn batches_round_trip() {
+        let mut batch = ReadingBatch::new();
+        for time in 0..5 {
+            batch.push(reading(time)).unwrap();
+        }
+
+        let json = serde_json::to_string(&batch).unwrap();
+        let decoded: ReadingBatch = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, batch);
+        assert_eq!(decoded.len(), 5);
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let message = ClientHostMessage::DeviceInfo(DeviceInfo::for_test(2));
+
+        let json = serde_json::to_string(&message).unwrap();
+        let decoded: ClientHostMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, message);
+    }
+
+    #[test]
+    fn overlong_batches_are_rejected() {
+        let mut readings = Vec::<Reading, {MAX_BATCH_SIZE + 1}>::new();
+        for time in 0..MAX_BATCH_SIZE as u32 + 1 {
+            readings.push(reading(time)).unwrap();
+        }
 
-        assert_eq!(incorrect, Err(DecodingError::IncorrectPrefixByte(4)));
+        let json = serde_json::to_string(&readings).unwrap();
+        assert!(serde_json::from_str::<ReadingBatch>(&json).is_err());
     }
 }
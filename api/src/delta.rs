@@ -0,0 +1,188 @@
+/*!
+  Stateful encoding of readings as time deltas.
+
+  Most edges are close together in time, so sending the time since the previous
+  reading as a varint takes 1-3 bytes instead of the 4 bytes of a full timestamp.
+  Since a lost frame would shift the time of every following compact reading, a
+  full `Reading` is sent every `FULL_READING_INTERVAL` readings and whenever the
+  delta is too large to be worth compressing.
+*/
+
+use data::{Reading, CompactReading, ClientHostMessage, VarInt};
+
+/// The maximum amount of compact readings sent between two full readings
+pub const FULL_READING_INTERVAL: u16 = 64;
+
+/// Deltas of this size or larger take as many bytes as a full timestamp
+const MAX_COMPACT_DELTA: u32 = 1 << 21;
+
+/**
+  Turns readings into `Reading` or `CompactReading` messages
+*/
+pub struct ReadingEncoder {
+    last_time: Option<u32>,
+    since_full_reading: u16,
+}
+
+impl ReadingEncoder {
+    pub fn new() -> Self {
+        Self {
+            last_time: None,
+            since_full_reading: 0,
+        }
+    }
+
+    /// Makes the next reading a full reading, for example when a new host connects
+    pub fn reset(&mut self) {
+        self.last_time = None;
+    }
+
+    pub fn encode(&mut self, reading: Reading) -> ClientHostMessage {
+        let previous = self.last_time;
+        self.last_time = Some(reading.time);
+
+        if let Some(previous) = previous {
+            let delta = reading.time.wrapping_sub(previous);
+            if delta < MAX_COMPACT_DELTA && self.since_full_reading < FULL_READING_INTERVAL {
+                self.since_full_reading += 1;
+                return ClientHostMessage::CompactReading(CompactReading {
+                    state: reading.state,
+                    delta: VarInt(delta),
+                    changed: reading.changed,
+                });
+            }
+        }
+
+        self.since_full_reading = 0;
+        ClientHostMessage::Reading(reading)
+    }
+}
+
+impl Default for ReadingEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/**
+  Turns `Reading` and `CompactReading` messages back into readings
+*/
+pub struct ReadingDecoder {
+    last_time: Option<u32>,
+}
+
+impl ReadingDecoder {
+    pub fn new() -> Self {
+        Self {
+            last_time: None
+        }
+    }
+
+    /// Forgets the previous reading, compact readings are dropped until the next full reading
+    pub fn reset(&mut self) {
+        self.last_time = None;
+    }
+
+    /**
+      Returns the reading contained in `message`, or `None` if it is not a
+      reading or is a compact reading without a preceding full reading
+    */
+    pub fn decode(&mut self, message: &ClientHostMessage) -> Option<Reading> {
+        match *message {
+            ClientHostMessage::Reading(ref reading) => {
+                self.last_time = Some(reading.time);
+                Some(*reading)
+            }
+            ClientHostMessage::CompactReading(ref compact) => {
+                let time = self.last_time?.wrapping_add(compact.delta.0);
+                self.last_time = Some(time);
+                Some(Reading {
+                    state: compact.state,
+                    time,
+                    changed: compact.changed,
+                })
+            }
+            _ => None
+        }
+    }
+}
+
+impl Default for ReadingDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod delta_tests {
+    use super::*;
+    use data::State;
+
+    fn reading(time: u32) -> Reading {
+        Reading::new(time, State::from_channels(&[time & 1 == 0, false]))
+    }
+
+    #[test]
+    fn first_reading_is_full() {
+        let mut encoder = ReadingEncoder::new();
+        assert_eq!(encoder.encode(reading(100)), ClientHostMessage::Reading(reading(100)));
+        assert_eq!(
+            encoder.encode(reading(150)),
+            ClientHostMessage::CompactReading(CompactReading {
+                state: reading(150).state,
+                delta: VarInt(50),
+                changed: reading(150).changed
+            })
+        );
+
+        encoder.reset();
+        assert_eq!(encoder.encode(reading(200)), ClientHostMessage::Reading(reading(200)));
+    }
+
+    #[test]
+    fn large_deltas_are_sent_as_full_readings() {
+        let mut encoder = ReadingEncoder::new();
+        encoder.encode(reading(0));
+        assert_eq!(
+            encoder.encode(reading(MAX_COMPACT_DELTA)),
+            ClientHostMessage::Reading(reading(MAX_COMPACT_DELTA))
+        );
+    }
+
+    #[test]
+    fn full_readings_are_sent_periodically() {
+        let mut encoder = ReadingEncoder::new();
+        let full_readings = (0..200)
+            .map(|i| encoder.encode(reading(i * 10)))
+            .filter(|message| matches!(*message, ClientHostMessage::Reading(_)))
+            .count();
+
+        assert_eq!(full_readings, 200 / (FULL_READING_INTERVAL as usize + 1) + 1);
+    }
+
+    #[test]
+    fn round_trip_across_wraparound() {
+        let mut encoder = ReadingEncoder::new();
+        let mut decoder = ReadingDecoder::new();
+
+        let start: u32 = 0xffff_ff00;
+        for i in 0..300 {
+            let original = reading(start.wrapping_add(i * 3));
+            let message = encoder.encode(original);
+            assert_eq!(decoder.decode(&message), Some(original));
+        }
+    }
+
+    #[test]
+    fn compact_reading_without_base_is_dropped() {
+        let mut encoder = ReadingEncoder::new();
+        let mut decoder = ReadingDecoder::new();
+
+        encoder.encode(reading(10));
+        let compact = encoder.encode(reading(20));
+
+        assert_eq!(decoder.decode(&compact), None);
+        assert_eq!(decoder.decode(&ClientHostMessage::CurrentTime(5)), None);
+    }
+}
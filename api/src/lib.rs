@@ -1,4 +1,23 @@
 #![no_std]
 
+extern crate heapless;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "std")]
+extern crate std;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+
+#[macro_use]
+mod macros;
+
+pub mod cobs;
+pub mod crc;
 pub mod data;
+pub mod delta;
+pub mod run;
+pub mod sampling;
+pub mod sequence;
+pub mod timestamp;
+pub mod trigger;
 pub use data::Message;
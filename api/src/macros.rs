@@ -0,0 +1,228 @@
+/**
+  Declares a struct and implements `Message` for it by encoding each field in
+  the order they are declared.
+
+  ```text
+  message_struct! {
+      #[derive(Debug, PartialEq)]
+      pub struct Overflow {
+          pub dropped: u32,
+          pub at_time: u32,
+      }
+  }
+  ```
+*/
+macro_rules! message_struct {
+    (
+        $(#[$meta:meta])*
+        pub struct $name:ident {
+            $( $(#[$field_meta:meta])* $field_vis:vis $field:ident : $field_type:ty ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        pub struct $name {
+            $( $(#[$field_meta])* $field_vis $field: $field_type ),*
+        }
+
+        impl $crate::data::Message<Self> for $name {
+            const MAX_ENCODED_LEN: usize = 0 $(
+                + <$field_type as $crate::data::Message<$field_type>>::MAX_ENCODED_LEN
+            )*;
+
+            fn encode(&self, buffer: &mut [u8])
+                -> Result<usize, $crate::data::EncodingError>
+            {
+                let mut used_bytes = 0;
+                $(
+                    used_bytes += $crate::data::Message::<$field_type>::encode(
+                        &self.$field,
+                        &mut buffer[used_bytes..]
+                    )?;
+                )*
+                Ok(used_bytes)
+            }
+
+            fn decode(bytes: &[u8], context: $crate::data::DecodingContext)
+                -> Result<(usize, Self), $crate::data::DecodingError>
+            {
+                let mut used_bytes = 0;
+                $(
+                    let (len, $field) =
+                        <$field_type as $crate::data::Message<$field_type>>::decode(
+                            &bytes[used_bytes..],
+                            context
+                        )?;
+                    used_bytes += len;
+                )*
+                Ok((used_bytes, $name { $($field),* }))
+            }
+        }
+    }
+}
+
+/**
+  Declares an enum where each variant is identified by a tag byte and optionally
+  carries a single `Message` as payload. Implements `Message` for it by encoding
+  the tag followed by the payload.
+
+  ```text
+  message_enum! {
+      #[derive(Debug, PartialEq)]
+      pub enum Command {
+          1 => Start,
+          2 => EnableChannel(u8),
+      }
+  }
+  ```
+
+  Using `frame_enum!` instead of `message_enum!` additionally wraps the encoded
+  message in a frame with a prefix byte and checksum, which is what is sent
+  over the wire, and implements `Resync` so it can be read by a `StreamDecoder`.
+*/
+macro_rules! message_enum {
+    ($($body:tt)*) => {
+        tagged_enum!{$($body)*}
+
+        tagged_enum!{@impl_message encode_tagged, decode_tagged, TAG_OVERHEAD, $($body)*}
+    }
+}
+
+macro_rules! frame_enum {
+    ($($body:tt)*) => {
+        tagged_enum!{$($body)*}
+
+        tagged_enum!{@impl_message encode_frame, decode_frame, FRAME_OVERHEAD, $($body)*}
+
+        tagged_enum!{@impl_resync $($body)*}
+    }
+}
+
+/**
+  Implementation of `message_enum!` and `frame_enum!`. Declares the enum and
+  implements `Tagged` for it
+*/
+macro_rules! tagged_enum {
+    (
+        @impl_message $encode:ident, $decode:ident, $overhead:ident,
+        $(#[$meta:meta])*
+        pub enum $name:ident { $($variants:tt)* }
+    ) => {
+        impl $crate::data::Message<Self> for $name {
+            const MAX_ENCODED_LEN: usize =
+                $crate::data::$overhead + <Self as $crate::data::Tagged>::MAX_PAYLOAD_LEN;
+
+            fn encode(&self, buffer: &mut [u8])
+                -> Result<usize, $crate::data::EncodingError>
+            {
+                $crate::data::$encode(self, buffer)
+            }
+
+            fn decode(bytes: &[u8], context: $crate::data::DecodingContext)
+                -> Result<(usize, Self), $crate::data::DecodingError>
+            {
+                $crate::data::$decode(bytes, context)
+            }
+        }
+    };
+    (
+        @impl_resync
+        $(#[$meta:meta])*
+        pub enum $name:ident { $($variants:tt)* }
+    ) => {
+        impl $crate::data::Resync for $name {
+            fn resync_offset(bytes: &[u8]) -> usize {
+                $crate::data::next_frame_start(bytes)
+            }
+        }
+    };
+    (@payload_len ($inner:ty)) => {
+        <$inner as $crate::data::Message<$inner>>::MAX_ENCODED_LEN
+    };
+    (@payload_len) => {
+        0
+    };
+    // `$val` is passed in so that the pattern and the expression of a match arm
+    // agree on the name of the payload
+    (@pattern $name:ident, $variant:ident, $val:ident ($inner:ty)) => {
+        $name::$variant(ref $val)
+    };
+    (@pattern $name:ident, $variant:ident, $val:ident) => {
+        $name::$variant
+    };
+    (@encode_payload $buffer:ident, $val:ident ($inner:ty)) => {
+        $crate::data::Message::<$inner>::encode($val, $buffer)
+    };
+    (@encode_payload $buffer:ident, $val:ident) => {
+        {
+            // Unused if none of the variants has a payload
+            let _ = $buffer;
+            Ok(0)
+        }
+    };
+    (@decode_payload $bytes:ident, $context:ident, $name:ident, $variant:ident ($inner:ty)) => {
+        {
+            let (len, val) =
+                <$inner as $crate::data::Message<$inner>>::decode($bytes, $context)?;
+            Ok((len, $name::$variant(val)))
+        }
+    };
+    (@decode_payload $bytes:ident, $context:ident, $name:ident, $variant:ident) => {
+        {
+            let _ = ($bytes, $context);
+            Ok((0, $name::$variant))
+        }
+    };
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident {
+            $(
+                $(#[$variant_meta:meta])*
+                $tag:literal => $variant:ident $(($inner:ty))?
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        pub enum $name {
+            $( $(#[$variant_meta])* $variant $(($inner))? ),*
+        }
+
+        impl $crate::data::Tagged for $name {
+            const MAX_PAYLOAD_LEN: usize = $crate::data::max_len(&[
+                $( tagged_enum!(@payload_len $(($inner))?) ),*
+            ]);
+
+            fn tag(&self) -> u8 {
+                match *self {
+                    $( $name::$variant { .. } => $tag ),*
+                }
+            }
+
+            fn encode_payload(&self, buffer: &mut [u8])
+                -> Result<usize, $crate::data::EncodingError>
+            {
+                match *self {
+                    $(
+                        tagged_enum!(@pattern $name, $variant, payload $(($inner))?) =>
+                            tagged_enum!(@encode_payload buffer, payload $(($inner))?)
+                    ),*
+                }
+            }
+
+            fn decode_payload(tag: u8, bytes: &[u8], context: $crate::data::DecodingContext)
+                -> Result<(usize, Self), $crate::data::DecodingError>
+            {
+                match tag {
+                    $(
+                        $tag => tagged_enum!(
+                            @decode_payload bytes, context, $name, $variant $(($inner))?
+                        ),
+                    )*
+                    byte => Err($crate::data::DecodingError::UnexpectedByte(
+                        byte,
+                        concat!(stringify!($name), " tag must be one of ", stringify!($($tag),*))
+                    ))
+                }
+            }
+        }
+    };
+}
@@ -0,0 +1,403 @@
+/*!
+  Run-length encoding of periodic signals.
+
+  A clock line produces an edge every period which would otherwise be sent as a
+  reading of its own. `RunEncoder` finds readings that are equally far apart and
+  either toggle the same channels or, when only one edge is recorded, repeat the
+  same state. It describes them with a single `ReadingRun` which the host
+  expands back into readings with `ReadingRun::readings`.
+
+  The encoding is lossless, readings are only part of a run if their times
+  match the period exactly. Runs are limited in duration so that a clock that
+  keeps running is still sent regularly.
+*/
+
+use data::{Reading, ReadingRun, State, VarInt};
+
+/// Shorter runs take more space than the readings in them and are sent as readings
+pub const MIN_RUN_LENGTH: u32 = 4;
+
+#[derive(Debug, PartialEq)]
+pub enum RunEvent {
+    /// A reading which is not part of a run
+    Reading(Reading),
+    Run(ReadingRun),
+}
+
+/// The run that new readings are added to
+struct PendingRun {
+    first: Reading,
+    last: Reading,
+    period: u32,
+    count: u32,
+    toggles: bool,
+}
+
+impl PendingRun {
+    fn new(reading: Reading) -> Self {
+        Self {
+            first: reading,
+            last: reading,
+            period: 0,
+            count: 1,
+            toggles: true,
+        }
+    }
+
+    /// Adds `reading` to the run if it continues it
+    fn extend(&mut self, reading: Reading, max_period: u32, max_duration: u32) -> bool {
+        let delta = reading.time.wrapping_sub(self.last.time);
+        let toggled = reading.state == toggle(&self.last);
+        let repeated = reading.state == self.last.state;
+        // The second reading decides the period and kind of the run
+        let (period_matches, state_matches) = if self.count == 1 {
+            (delta != 0 && delta <= max_period, toggled || repeated)
+        }
+        else {
+            (delta == self.period, if self.toggles { toggled } else { repeated })
+        };
+
+        let continues = period_matches
+            && self.count < u32::MAX
+            && reading.time.wrapping_sub(self.first.time) <= max_duration
+            && self.first.changed.0 != 0
+            && reading.changed == self.first.changed
+            && state_matches;
+
+        if continues {
+            if self.count == 1 {
+                self.toggles = toggled;
+            }
+            self.last = reading;
+            self.period = delta;
+            self.count += 1;
+        }
+        continues
+    }
+
+    fn into_run(self) -> ReadingRun {
+        ReadingRun {
+            first: self.first,
+            period: VarInt(self.period),
+            count: VarInt(self.count),
+            toggles: self.toggles,
+        }
+    }
+}
+
+/// The state after the edges of the channels that changed in `reading`
+fn toggle(reading: &Reading) -> State {
+    State::new(
+        reading.state.channel_count(),
+        reading.state.bits() ^ reading.changed.0
+    )
+}
+
+/**
+  Groups readings into runs.
+
+  Readings are added with `push` and come out again, either on their own or as
+  part of a `ReadingRun`, from `pull` once the run they belong to has ended.
+*/
+pub struct RunEncoder {
+    max_period: u32,
+    max_duration: u32,
+    pending: Option<PendingRun>,
+    // A run that has ended and the amount of its readings that have been pulled
+    finished: Option<(ReadingRun, u32)>,
+}
+
+impl RunEncoder {
+    /**
+      Readings more than `max_period` ticks apart are never part of the same
+      run, and runs end once they are `max_duration` ticks long. `max_duration`
+      must be less than 2^31 for the host to place the runs in time
+    */
+    pub fn new(max_period: u32, max_duration: u32) -> Self {
+        Self {
+            max_period,
+            max_duration,
+            pending: None,
+            finished: None,
+        }
+    }
+
+    /// Adds the next reading. Everything must be pulled before the next push
+    pub fn push(&mut self, reading: Reading) {
+        if let Some(ref mut pending) = self.pending {
+            if pending.extend(reading, self.max_period, self.max_duration) {
+                return;
+            }
+        }
+
+        self.flush();
+        self.pending = Some(PendingRun::new(reading));
+    }
+
+    /**
+      Ends the current run if its next reading should have arrived before `now`,
+      so that readings are not held back after the signal stops
+    */
+    pub fn end_stale_run(&mut self, now: u32) {
+        let stale = match self.pending {
+            Some(ref pending) => {
+                let limit = if pending.count == 1 {
+                    self.max_period
+                }
+                else {
+                    pending.period
+                };
+                now.wrapping_sub(pending.last.time) > limit
+            }
+            None => false
+        };
+
+        if stale {
+            self.flush();
+        }
+    }
+
+    /// Ends the current run. Everything must be pulled before the next push
+    pub fn flush(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            self.finished = Some((pending.into_run(), 0));
+        }
+    }
+
+    pub fn pull(&mut self) -> Option<RunEvent> {
+        let (run, pulled) = self.finished.take()?;
+        if run.count.0 >= MIN_RUN_LENGTH {
+            return Some(RunEvent::Run(run));
+        }
+
+        let reading = run.readings().nth(pulled as usize)?;
+        if pulled + 1 < run.count.0 {
+            self.finished = Some((run, pulled + 1));
+        }
+        Some(RunEvent::Reading(reading))
+    }
+}
+
+impl ReadingRun {
+    pub fn readings(&self) -> RunReadings {
+        RunReadings {
+            next: self.first,
+            period: self.period.0,
+            remaining: self.count.0,
+            toggles: self.toggles,
+        }
+    }
+}
+
+/// The readings described by a `ReadingRun`
+pub struct RunReadings {
+    next: Reading,
+    period: u32,
+    remaining: u32,
+    toggles: bool,
+}
+
+impl Iterator for RunReadings {
+    type Item = Reading;
+
+    fn next(&mut self) -> Option<Reading> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let reading = self.next;
+        self.next = Reading {
+            state: if self.toggles { toggle(&reading) } else { reading.state },
+            time: reading.time.wrapping_add(self.period),
+            changed: reading.changed,
+        };
+        Some(reading)
+    }
+}
+
+
+#[cfg(test)]
+mod run_tests {
+    use super::*;
+    use data::ChannelMask;
+
+    const MAX_PERIOD: u32 = 1000;
+    const MAX_DURATION: u32 = 100_000;
+
+    /// `count` edges on channel 0 of a clock with the data line high
+    fn clock(start: u32, period: u32, count: u32) -> impl Iterator<Item = Reading> {
+        (0..count).map(move |i| Reading {
+            state: State::new(2, 0b10 | (i & 1)),
+            time: start.wrapping_add(i * period),
+            changed: ChannelMask(0b01)
+        })
+    }
+
+    /// `count` rising edges on channel 0 of a clock with the data line high
+    fn rising_clock(start: u32, period: u32, count: u32) -> impl Iterator<Item = Reading> {
+        (0..count).map(move |i| Reading {
+            state: State::new(2, 0b11),
+            time: start.wrapping_add(i * period),
+            changed: ChannelMask(0b01)
+        })
+    }
+
+    fn pull_all(encoder: &mut RunEncoder, events: &mut [Option<RunEvent>; 16], used: &mut usize) {
+        while let Some(event) = encoder.pull() {
+            events[*used] = Some(event);
+            *used += 1;
+        }
+    }
+
+    /// Pushes all `readings` and returns everything pulled from the encoder
+    fn encode(readings: impl Iterator<Item = Reading>) -> ([Option<RunEvent>; 16], usize) {
+        let mut encoder = RunEncoder::new(MAX_PERIOD, MAX_DURATION);
+        let mut events = Default::default();
+        let mut used = 0;
+        for reading in readings {
+            encoder.push(reading);
+            pull_all(&mut encoder, &mut events, &mut used);
+        }
+        encoder.flush();
+        pull_all(&mut encoder, &mut events, &mut used);
+        (events, used)
+    }
+
+    #[test]
+    fn clock_becomes_run() {
+        let (events, used) = encode(clock(100, 50, 1000));
+        assert_eq!(used, 1);
+        match events[0] {
+            Some(RunEvent::Run(ref run)) => {
+                assert_eq!(run.period, VarInt(50));
+                assert_eq!(run.count, VarInt(1000));
+                assert!(run.readings().eq(clock(100, 50, 1000)));
+            }
+            ref other => panic!("Expected a run, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn long_runs_are_sent_while_running() {
+        let mut encoder = RunEncoder::new(MAX_PERIOD, MAX_DURATION);
+        let mut readings = clock(100, 50, 10_000);
+        // The run is ended by the first reading more than `MAX_DURATION` after its start
+        for reading in readings.by_ref().take(2002) {
+            assert_eq!(encoder.pull(), None);
+            encoder.push(reading);
+        }
+        match encoder.pull() {
+            Some(RunEvent::Run(ref run)) => {
+                assert_eq!(run.count, VarInt(2001));
+                assert!(run.readings().eq(clock(100, 50, 2001)));
+            }
+            ref other => panic!("Expected a run, got {:?}", other)
+        }
+
+        // The clock continues in a new run
+        encoder.push(readings.next().unwrap());
+        assert_eq!(encoder.pull(), None);
+    }
+
+    #[test]
+    fn single_edge_clock_becomes_run() {
+        let (events, used) = encode(rising_clock(100, 50, 1000));
+        assert_eq!(used, 1);
+        match events[0] {
+            Some(RunEvent::Run(ref run)) => {
+                assert!(!run.toggles);
+                assert_eq!(run.count, VarInt(1000));
+                assert!(run.readings().eq(rising_clock(100, 50, 1000)));
+            }
+            ref other => panic!("Expected a run, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn run_kinds_are_not_mixed() {
+        // Two toggling edges followed by repeated ones
+        let readings = clock(100, 50, 2).chain(rising_clock(200, 50, 5));
+        let (events, used) = encode(readings);
+        assert_eq!(used, 3);
+        match events[2] {
+            Some(RunEvent::Run(ref run)) => {
+                assert!(!run.toggles);
+                assert!(run.readings().eq(rising_clock(200, 50, 5)));
+            }
+            ref other => panic!("Expected a run, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn short_runs_are_readings() {
+        let (events, used) = encode(clock(100, 50, 3));
+        assert_eq!(used, 3);
+        for (event, reading) in events.iter().zip(clock(100, 50, 3)) {
+            assert_eq!(*event, Some(RunEvent::Reading(reading)));
+        }
+    }
+
+    #[test]
+    fn period_changes_end_runs() {
+        let readings = clock(100, 50, 5).chain(clock(400, 60, 6));
+        let (events, used) = encode(readings);
+        assert_eq!(used, 2);
+        match (&events[0], &events[1]) {
+            (Some(RunEvent::Run(first)), Some(RunEvent::Run(second))) => {
+                assert!(first.readings().eq(clock(100, 50, 5)));
+                assert!(second.readings().eq(clock(400, 60, 6)));
+            }
+            other => panic!("Expected two runs, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn runs_need_consistent_states() {
+        // The data line toggles while only the clock had an edge
+        let readings = clock(100, 50, 5).map(|reading| Reading {
+            state: State::new(2, ((reading.time / 50) & 1) << 1),
+            ..reading
+        });
+        let (_, used) = encode(readings);
+        assert_eq!(used, 5);
+    }
+
+    #[test]
+    fn distant_readings_are_not_runs() {
+        let (_, used) = encode(clock(100, MAX_PERIOD + 1, 5));
+        assert_eq!(used, 5);
+    }
+
+    #[test]
+    fn runs_wrap_around() {
+        let (events, used) = encode(clock(0xffff_ff00, 0x40, 8));
+        assert_eq!(used, 1);
+        match events[0] {
+            Some(RunEvent::Run(ref run)) => {
+                assert!(run.readings().eq(clock(0xffff_ff00, 0x40, 8)));
+            }
+            ref other => panic!("Expected a run, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn stale_runs_end() {
+        let mut encoder = RunEncoder::new(MAX_PERIOD, MAX_DURATION);
+        for reading in clock(100, 50, 5) {
+            encoder.push(reading);
+        }
+        // The next edge is due at 350
+        encoder.end_stale_run(350);
+        assert_eq!(encoder.pull(), None);
+        encoder.end_stale_run(351);
+        assert!(encoder.pull().is_some());
+
+        // A single reading waits for as long as a run could be started
+        encoder.push(clock(2000, 1, 1).next().unwrap());
+        encoder.end_stale_run(2000 + MAX_PERIOD);
+        assert_eq!(encoder.pull(), None);
+        encoder.end_stale_run(2001 + MAX_PERIOD);
+        assert!(encoder.pull().is_some());
+    }
+}
@@ -0,0 +1,138 @@
+/*!
+  Fixed-rate sampling.
+
+  Recording edges costs an interrupt per edge, which limits how fast signals
+  can be. Instead the device can let a timer trigger DMA transfers from the
+  GPIO input register, which samples every channel at a fixed rate. The raw
+  samples are sent in `SampleBlock`s and `SampleDecoder` turns them back into a
+  reading per edge.
+*/
+
+use heapless::Vec;
+
+use data::{ChannelMask, Reading, SampleBlock, State, VarInt, SAMPLE_BLOCK_SIZE};
+
+impl SampleBlock {
+    /// Creates a block of the first `SAMPLE_BLOCK_SIZE` samples
+    pub fn new(start_time: u32, period: u32, samples: &[u16]) -> Self {
+        let len = samples.len().min(SAMPLE_BLOCK_SIZE);
+        Self {
+            start_time,
+            period: VarInt(period),
+            // Fits since the length is limited
+            samples: Vec::from_slice(&samples[..len]).unwrap_or_default(),
+        }
+    }
+}
+
+/// Picks the channels out of a raw sample, `lines[n]` is the bit that holds channel `n`
+pub fn sample_state(sample: u16, lines: &[u8]) -> State {
+    let bits = lines.iter()
+        .enumerate()
+        .filter(|&(_, &line)| line < 16 && sample & (1 << line) != 0)
+        .fold(0, |bits, (channel, _)| bits | (1 << channel));
+    State::new(lines.len() as u8, bits)
+}
+
+/**
+  Turns sample blocks into readings at the samples where a channel changed
+*/
+pub struct SampleDecoder {
+    previous: Option<State>,
+}
+
+impl SampleDecoder {
+    pub fn new() -> Self {
+        Self {
+            previous: None
+        }
+    }
+
+    /// Forgets the previous sample, for example when sampling is restarted
+    pub fn reset(&mut self) {
+        self.previous = None;
+    }
+
+    /**
+      Calls `on_reading` for every sample in `block` where a channel changed.
+      The first sample after a reset is always a reading since there is nothing
+      to compare it to
+    */
+    pub fn decode<F>(&mut self, block: &SampleBlock, lines: &[u8], mut on_reading: F)
+        where F: FnMut(Reading)
+    {
+        let mut time = block.start_time;
+        for &sample in &block.samples {
+            let state = sample_state(sample, lines);
+            let changed = self.previous.map(|previous| previous.bits() ^ state.bits());
+            if changed != Some(0) {
+                on_reading(Reading {
+                    state,
+                    time,
+                    changed: ChannelMask(changed.unwrap_or(0)),
+                });
+            }
+
+            self.previous = Some(state);
+            time = time.wrapping_add(block.period.0);
+        }
+    }
+}
+
+impl Default for SampleDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod sampling_tests {
+    use super::*;
+
+    // Channel 0 is on pin 8 and channel 1 on pin 0
+    const LINES: [u8; 2] = [8, 0];
+
+    fn block(start_time: u32, samples: &[u16]) -> SampleBlock {
+        SampleBlock::new(start_time, 10, samples)
+    }
+
+    fn decode(decoder: &mut SampleDecoder, block: &SampleBlock) -> Vec<Reading, 16> {
+        let mut readings = Vec::new();
+        decoder.decode(block, &LINES, |reading| readings.push(reading).unwrap());
+        readings
+    }
+
+    #[test]
+    fn lines_are_mapped_to_channels() {
+        assert_eq!(sample_state(0x0100, &LINES), State::new(2, 0b01));
+        assert_eq!(sample_state(0x0001, &LINES), State::new(2, 0b10));
+        // Other pins are ignored
+        assert_eq!(sample_state(0xfefe, &LINES), State::new(2, 0b00));
+    }
+
+    #[test]
+    fn only_changes_are_readings() {
+        let mut decoder = SampleDecoder::new();
+        let readings = decode(&mut decoder, &block(1000, &[0x0100, 0x0100, 0x0101, 0x0101, 0x0000]));
+        assert_eq!(&readings[..], &[
+            Reading {state: State::new(2, 0b01), time: 1000, changed: ChannelMask(0b00)},
+            Reading {state: State::new(2, 0b11), time: 1020, changed: ChannelMask(0b10)},
+            Reading {state: State::new(2, 0b00), time: 1040, changed: ChannelMask(0b11)},
+        ]);
+    }
+
+    #[test]
+    fn changes_between_blocks_are_found() {
+        let mut decoder = SampleDecoder::new();
+        decode(&mut decoder, &block(1000, &[0x0000, 0x0000]));
+        let readings = decode(&mut decoder, &block(1020, &[0x0000, 0x0100]));
+        assert_eq!(&readings[..], &[
+            Reading {state: State::new(2, 0b01), time: 1030, changed: ChannelMask(0b01)},
+        ]);
+
+        decoder.reset();
+        let readings = decode(&mut decoder, &block(2000, &[0x0100]));
+        assert_eq!(readings.len(), 1);
+    }
+}
@@ -0,0 +1,121 @@
+/*!
+  Detection of lost and duplicated frames.
+
+  Every `ClientHostFrame` carries an 8 bit sequence number which the device
+  increases by one for each frame it sends. A jump in the numbers means that
+  frames were lost on the way, for example by a flaky USB-serial bridge, while a
+  number that was recently seen means a frame arrived twice.
+*/
+
+/// Frames this far behind the expected sequence number are treated as duplicates
+const DUPLICATE_WINDOW: u8 = 32;
+
+#[derive(Debug, PartialEq)]
+pub enum SequenceCheck {
+    /// The frame directly follows the previous one
+    InOrder,
+    /// This many frames were lost before the frame
+    Lost(u8),
+    /// The frame was already received and should be dropped
+    Duplicate,
+}
+
+/**
+  Keeps track of the sequence numbers of received frames.
+
+  Losing more than `256 - DUPLICATE_WINDOW` frames in a row is indistinguishable
+  from losing fewer, the amount of lost frames is only correct modulo 256.
+*/
+pub struct SequenceTracker {
+    expected: Option<u8>,
+}
+
+impl SequenceTracker {
+    pub fn new() -> Self {
+        Self {
+            expected: None
+        }
+    }
+
+    /// Forgets the previous frames, for example when the device restarts
+    pub fn reset(&mut self) {
+        self.expected = None;
+    }
+
+    pub fn check(&mut self, sequence: u8) -> SequenceCheck {
+        let expected = match self.expected {
+            Some(expected) => expected,
+            None => {
+                self.expected = Some(sequence.wrapping_add(1));
+                return SequenceCheck::InOrder;
+            }
+        };
+
+        let distance = sequence.wrapping_sub(expected);
+        if distance >= 0u8.wrapping_sub(DUPLICATE_WINDOW) {
+            return SequenceCheck::Duplicate;
+        }
+
+        self.expected = Some(sequence.wrapping_add(1));
+        if distance == 0 {
+            SequenceCheck::InOrder
+        }
+        else {
+            SequenceCheck::Lost(distance)
+        }
+    }
+}
+
+impl Default for SequenceTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod sequence_tests {
+    use super::*;
+
+    #[test]
+    fn consecutive_frames_are_in_order() {
+        let mut tracker = SequenceTracker::new();
+        for sequence in 250..=255 {
+            assert_eq!(tracker.check(sequence), SequenceCheck::InOrder);
+        }
+        // Wrapping around is not a gap
+        assert_eq!(tracker.check(0), SequenceCheck::InOrder);
+        assert_eq!(tracker.check(1), SequenceCheck::InOrder);
+    }
+
+    #[test]
+    fn gaps_are_counted() {
+        let mut tracker = SequenceTracker::new();
+        tracker.check(10);
+        assert_eq!(tracker.check(14), SequenceCheck::Lost(3));
+        assert_eq!(tracker.check(15), SequenceCheck::InOrder);
+        // Across the wraparound
+        tracker.reset();
+        tracker.check(254);
+        assert_eq!(tracker.check(2), SequenceCheck::Lost(3));
+    }
+
+    #[test]
+    fn repeated_frames_are_duplicates() {
+        let mut tracker = SequenceTracker::new();
+        tracker.check(0);
+        tracker.check(1);
+        assert_eq!(tracker.check(1), SequenceCheck::Duplicate);
+        assert_eq!(tracker.check(0), SequenceCheck::Duplicate);
+        assert_eq!(tracker.check(2), SequenceCheck::InOrder);
+    }
+
+    #[test]
+    fn reset_accepts_any_sequence() {
+        let mut tracker = SequenceTracker::new();
+        tracker.check(100);
+        tracker.reset();
+        assert_eq!(tracker.check(0), SequenceCheck::InOrder);
+        assert_eq!(tracker.check(1), SequenceCheck::InOrder);
+    }
+}
@@ -0,0 +1,111 @@
+/*!
+  Extension of the 32 bit device timestamps to 64 bits.
+
+  The device timer wraps around every 2^32 ticks, which is about 60 seconds at
+  72 MHz. As long as timestamps are fed to the `TimestampUnwrapper` at least
+  every 2^31 ticks, which the periodic `CurrentTime` messages ensure, the
+  number of wraparounds can be tracked and timestamps stay monotonic.
+*/
+
+/**
+  Turns wrapping 32 bit tick counts into non-wrapping 64 bit ones.
+
+  Timestamps are interpreted relative to the latest timestamp seen, so readings
+  that arrive slightly out of order around a wraparound are still placed
+  correctly.
+*/
+pub struct TimestampUnwrapper {
+    latest: Option<u64>,
+}
+
+impl TimestampUnwrapper {
+    pub fn new() -> Self {
+        Self {
+            latest: None
+        }
+    }
+
+    /// Forgets all previous timestamps, for example when the device restarts
+    pub fn reset(&mut self) {
+        self.latest = None;
+    }
+
+    pub fn extend(&mut self, ticks: u32) -> u64 {
+        let latest = match self.latest {
+            Some(latest) => latest,
+            None => {
+                self.latest = Some(ticks as u64);
+                return ticks as u64;
+            }
+        };
+
+        // The signed distance to the latest timestamp, which is correct as long
+        // as the two are less than 2^31 ticks apart
+        let difference = ticks.wrapping_sub(latest as u32) as i32 as i64;
+        let extended = if difference < 0 && (-difference) as u64 > latest {
+            // Before the first timestamp we saw, nothing sensible can be done
+            0
+        }
+        else {
+            (latest as i64 + difference) as u64
+        };
+
+        if extended > latest {
+            self.latest = Some(extended);
+        }
+        extended
+    }
+}
+
+impl Default for TimestampUnwrapper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod timestamp_tests {
+    use super::*;
+
+    #[test]
+    fn timestamps_without_wraparound_are_unchanged() {
+        let mut unwrapper = TimestampUnwrapper::new();
+        assert_eq!(unwrapper.extend(100), 100);
+        assert_eq!(unwrapper.extend(5000), 5000);
+        assert_eq!(unwrapper.extend(4000), 4000);
+    }
+
+    #[test]
+    fn wraparounds_are_counted() {
+        let mut unwrapper = TimestampUnwrapper::new();
+        let mut ticks: u32 = 0;
+        let mut expected: u64 = 0;
+        // Step through several wraparounds in steps smaller than 2^31
+        for _ in 0..40 {
+            ticks = ticks.wrapping_add(0x4000_0000);
+            expected += 0x4000_0000;
+            assert_eq!(unwrapper.extend(ticks), expected);
+        }
+    }
+
+    #[test]
+    fn late_timestamps_before_wraparound_stay_in_previous_period() {
+        let mut unwrapper = TimestampUnwrapper::new();
+        unwrapper.extend(0xffff_ff00);
+        assert_eq!(unwrapper.extend(0x10), 0x1_0000_0010);
+        // A reading taken before the wraparound but received after it
+        assert_eq!(unwrapper.extend(0xffff_ff80), 0xffff_ff80);
+        assert_eq!(unwrapper.extend(0x20), 0x1_0000_0020);
+    }
+
+    #[test]
+    fn timestamps_before_the_first_one_saturate() {
+        let mut unwrapper = TimestampUnwrapper::new();
+        unwrapper.extend(10);
+        assert_eq!(unwrapper.extend(0xffff_fff0), 0);
+
+        unwrapper.reset();
+        assert_eq!(unwrapper.extend(0xffff_fff0), 0xffff_fff0);
+    }
+}
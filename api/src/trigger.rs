@@ -0,0 +1,360 @@
+/*!
+  Triggered captures.
+
+  Streaming every reading is limited by the bandwidth of the serial port, so
+  short bursts of edges are easily lost to overflows. In capture mode the
+  device instead keeps the latest readings in RAM until a `Trigger` fires,
+  collects a number of readings after it, and only then sends the whole window.
+*/
+
+use heapless::Deque;
+
+use data::{
+    CaptureConfig,
+    CaptureWindow,
+    EdgeMode,
+    Pattern,
+    Reading,
+    State,
+    Trigger,
+    VarInt
+};
+
+impl Pattern {
+    pub fn matches(&self, state: &State) -> bool {
+        state.bits() & self.mask.0 == self.value.0 & self.mask.0
+    }
+}
+
+/**
+  Decides which reading fires a trigger
+*/
+pub struct TriggerEvaluator {
+    trigger: Trigger,
+    // Patterns only fire when they start matching
+    previous: Option<State>,
+}
+
+impl TriggerEvaluator {
+    pub fn new(trigger: Trigger) -> Self {
+        Self {
+            trigger,
+            previous: None,
+        }
+    }
+
+    pub fn check(&mut self, reading: &Reading) -> bool {
+        let previous = self.previous.replace(reading.state);
+        match self.trigger {
+            Trigger::Edge(ref edge) => {
+                let level = reading.state.channel(edge.channel);
+                reading.changed.contains(edge.channel) && match edge.mode {
+                    EdgeMode::Rising => level,
+                    EdgeMode::Falling => !level,
+                    EdgeMode::Both => true,
+                }
+            }
+            Trigger::Pattern(ref pattern) => {
+                // Without a previous state it is unknown if the pattern just
+                // started matching
+                let was_matching = match previous {
+                    Some(previous) => pattern.matches(&previous),
+                    None => true
+                };
+                !was_matching && pattern.matches(&reading.state)
+            }
+        }
+    }
+}
+
+enum Mode {
+    Idle,
+    Armed(TriggerEvaluator),
+    Triggered {
+        window: CaptureWindow,
+        remaining: u32,
+    },
+    // The window is taken when it is announced
+    Complete(Option<CaptureWindow>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CaptureEvent {
+    /// Describes the readings that follow
+    Window(CaptureWindow),
+    Reading(Reading),
+}
+
+/**
+  Collects the readings around a trigger. Holds at most `N` readings.
+
+  A capture is only taken once. When it is complete it is taken out with
+  `pull`, readings pushed until then are dropped. Afterwards the capture is no
+  longer active and readings should be streamed again.
+*/
+pub struct Capture<const N: usize> {
+    mode: Mode,
+    readings: Deque<Reading, N>,
+    pre_trigger: usize,
+    post_trigger: u32,
+}
+
+impl<const N: usize> Capture<N> {
+    pub const fn new() -> Self {
+        Self {
+            mode: Mode::Idle,
+            readings: Deque::new(),
+            pre_trigger: 0,
+            post_trigger: 0,
+        }
+    }
+
+    /**
+      Starts a new capture. A window larger than `N` readings is shortened,
+      post-trigger readings are kept over pre-trigger ones
+    */
+    pub fn arm(&mut self, config: &CaptureConfig) {
+        let post_trigger = (config.post_trigger.0 as usize).min(N.saturating_sub(1));
+        let pre_trigger = (config.pre_trigger.0 as usize)
+            .min(N.saturating_sub(1 + post_trigger));
+
+        self.readings.clear();
+        self.pre_trigger = pre_trigger;
+        self.post_trigger = post_trigger as u32;
+        self.mode = Mode::Armed(TriggerEvaluator::new(config.trigger.clone()));
+    }
+
+    /// Stops capturing, readings should be streamed again
+    pub fn disarm(&mut self) {
+        self.readings.clear();
+        self.mode = Mode::Idle;
+    }
+
+    /// Returns true if readings should be pushed into the capture instead of being streamed
+    pub fn is_active(&self) -> bool {
+        !matches!(self.mode, Mode::Idle)
+    }
+
+    pub fn push(&mut self, reading: Reading) {
+        let fired = match self.mode {
+            Mode::Idle | Mode::Complete(_) => return,
+            Mode::Armed(ref mut trigger) => trigger.check(&reading),
+            Mode::Triggered {ref mut remaining, ..} => {
+                let _ = self.readings.push_back(reading);
+                *remaining -= 1;
+                if *remaining == 0 {
+                    self.complete();
+                }
+                return;
+            }
+        };
+
+        if fired {
+            let window = CaptureWindow {
+                trigger_time: reading.time,
+                pre_trigger: VarInt(self.readings.len() as u32),
+                post_trigger: VarInt(self.post_trigger),
+            };
+            let _ = self.readings.push_back(reading);
+            self.mode = Mode::Triggered {
+                window,
+                remaining: self.post_trigger
+            };
+            if self.post_trigger == 0 {
+                self.complete();
+            }
+        }
+        else {
+            // Keep the latest `pre_trigger` readings
+            if self.readings.len() >= self.pre_trigger {
+                self.readings.pop_front();
+            }
+            if self.readings.len() < self.pre_trigger {
+                let _ = self.readings.push_back(reading);
+            }
+        }
+    }
+
+    /**
+      Returns the events of a complete capture: a `Window` followed by the
+      readings in it, oldest first
+    */
+    pub fn pull(&mut self) -> Option<CaptureEvent> {
+        match self.mode {
+            Mode::Complete(ref mut window) => {
+                if let Some(window) = window.take() {
+                    return Some(CaptureEvent::Window(window));
+                }
+            }
+            _ => return None
+        }
+        let reading = self.readings.pop_front();
+        if reading.is_none() {
+            // Everything was taken out
+            self.mode = Mode::Idle;
+        }
+        reading.map(CaptureEvent::Reading)
+    }
+
+    fn complete(&mut self) {
+        let mode = core::mem::replace(&mut self.mode, Mode::Idle);
+        self.mode = match mode {
+            Mode::Triggered {window, ..} => Mode::Complete(Some(window)),
+            mode => mode
+        };
+    }
+}
+
+impl<const N: usize> Default for Capture<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod trigger_tests {
+    use super::*;
+    use data::{ChannelEdgeMode, ChannelMask};
+
+    /// A reading where the channels in `changed` changed to the values in `bits`
+    fn reading(time: u32, bits: u32, changed: u32) -> Reading {
+        Reading {
+            state: State::new(4, bits),
+            time,
+            changed: ChannelMask(changed)
+        }
+    }
+
+    fn edge(channel: u8, mode: EdgeMode) -> Trigger {
+        Trigger::Edge(ChannelEdgeMode {channel, mode})
+    }
+
+    fn config(trigger: Trigger, pre_trigger: u32, post_trigger: u32) -> CaptureConfig {
+        CaptureConfig {
+            trigger,
+            pre_trigger: VarInt(pre_trigger),
+            post_trigger: VarInt(post_trigger)
+        }
+    }
+
+    #[test]
+    fn edge_triggers_fire_on_their_edge() {
+        let mut rising = TriggerEvaluator::new(edge(1, EdgeMode::Rising));
+        let mut falling = TriggerEvaluator::new(edge(1, EdgeMode::Falling));
+        let mut both = TriggerEvaluator::new(edge(1, EdgeMode::Both));
+
+        // Channel 0 rises while channel 1 is high
+        let other_channel = reading(0, 0b11, 0b01);
+        assert!(!rising.check(&other_channel));
+        assert!(!both.check(&other_channel));
+
+        let rising_edge = reading(10, 0b10, 0b10);
+        assert!(rising.check(&rising_edge));
+        assert!(!falling.check(&rising_edge));
+        assert!(both.check(&rising_edge));
+
+        let falling_edge = reading(20, 0b00, 0b10);
+        assert!(!rising.check(&falling_edge));
+        assert!(falling.check(&falling_edge));
+        assert!(both.check(&falling_edge));
+    }
+
+    #[test]
+    fn patterns_fire_when_they_start_matching() {
+        // Channel 0 high and channel 2 low
+        let pattern = Pattern {mask: ChannelMask(0b101), value: ChannelMask(0b001)};
+        assert!(pattern.matches(&State::new(4, 0b1011)));
+        assert!(!pattern.matches(&State::new(4, 0b0101)));
+
+        let mut trigger = TriggerEvaluator::new(Trigger::Pattern(pattern));
+        // The first reading only tells what the state was
+        assert!(!trigger.check(&reading(0, 0b001, 0b001)));
+        assert!(!trigger.check(&reading(10, 0b101, 0b100)));
+        assert!(trigger.check(&reading(20, 0b001, 0b100)));
+        // Still matching
+        assert!(!trigger.check(&reading(30, 0b011, 0b010)));
+    }
+
+    /// Pulls the whole capture, returning the window and the amount of readings
+    fn pull_capture<const N: usize>(capture: &mut Capture<N>) -> (CaptureWindow, [u32; N], usize) {
+        let window = match capture.pull() {
+            Some(CaptureEvent::Window(window)) => window,
+            other => panic!("Expected a window, got {:?}", other)
+        };
+        let mut times = [0; N];
+        let mut count = 0;
+        while let Some(event) = capture.pull() {
+            match event {
+                CaptureEvent::Reading(reading) => times[count] = reading.time,
+                other => panic!("Expected a reading, got {:?}", other)
+            }
+            count += 1;
+        }
+        (window, times, count)
+    }
+
+    #[test]
+    fn readings_around_the_trigger_are_captured() {
+        let mut capture = Capture::<16>::new();
+        assert!(!capture.is_active());
+        capture.arm(&config(edge(0, EdgeMode::Rising), 3, 2));
+        assert!(capture.is_active());
+        capture.disarm();
+        assert!(!capture.is_active());
+        capture.arm(&config(edge(0, EdgeMode::Rising), 3, 2));
+
+        // Channel 0 only rises at time 7
+        for time in 0..9 {
+            let level = if time == 7 { 1 } else { 0 };
+            capture.push(reading(time, level, 0b1));
+            assert_eq!(capture.pull(), None);
+        }
+        // Completes the capture, the reading after it is dropped
+        capture.push(reading(9, 0, 0b1));
+        capture.push(reading(10, 0, 0b1));
+
+        let (window, times, count) = pull_capture(&mut capture);
+        assert_eq!(window, CaptureWindow {
+            trigger_time: 7,
+            pre_trigger: VarInt(3),
+            post_trigger: VarInt(2)
+        });
+        assert_eq!(&times[..count], &[4, 5, 6, 7, 8, 9]);
+
+        // The capture is over, readings are streamed again
+        assert!(!capture.is_active());
+        capture.push(reading(11, 1, 0b1));
+        assert_eq!(capture.pull(), None);
+    }
+
+    #[test]
+    fn early_triggers_have_shorter_windows() {
+        let mut capture = Capture::<16>::new();
+        capture.arm(&config(edge(0, EdgeMode::Both), 5, 0));
+        capture.push(reading(0, 0b0, 0b0));
+        capture.push(reading(1, 0b1, 0b1));
+
+        let (window, times, count) = pull_capture(&mut capture);
+        assert_eq!(window.pre_trigger, VarInt(1));
+        assert_eq!(window.post_trigger, VarInt(0));
+        assert_eq!(&times[..count], &[0, 1]);
+    }
+
+    #[test]
+    fn large_windows_are_shortened() {
+        let mut capture = Capture::<4>::new();
+        capture.arm(&config(edge(0, EdgeMode::Both), 100, 2));
+        for time in 0..10 {
+            capture.push(reading(time, 0, 0));
+        }
+        capture.push(reading(10, 0b1, 0b1));
+        capture.push(reading(11, 0, 0));
+        capture.push(reading(12, 0, 0));
+
+        let (window, times, count) = pull_capture(&mut capture);
+        assert_eq!(window.pre_trigger, VarInt(1));
+        assert_eq!(window.post_trigger, VarInt(2));
+        assert_eq!(&times[..count], &[9, 10, 11, 12]);
+    }
+}
@@ -42,9 +42,33 @@ update msg model =
                     Ok (CurrentTime time) ->
                         let
                             oldReading = model.currentReading
-                            newReading = {oldReading | time = time}
+                            -- Only the time passed, none of the channels changed
+                            newReading =
+                                { oldReading
+                                    | time = time
+                                    , changed = List.map (always False) oldReading.changed
+                                }
                         in
                             ({model | currentReading = newReading}, Cmd.none)
+                    Ok (NewDeviceInfo info) ->
+                        -- The device was restarted, its old readings are from another run
+                        ({model | deviceInfo = Just info, readings = [], gaps = []}, Cmd.none)
+                    Ok (NewGap gap) ->
+                        ({model | gaps = model.gaps ++ [gap]}, Cmd.none)
+                    Ok (NewLostFrames lostFrames) ->
+                        ({model | lostFrames = lostFrames.totalLost}, Cmd.none)
+                    Ok (NewDeviceError error) ->
+                        ({model | lastError = Just error}, Cmd.none)
+                    Ok (NewClockSync clockSync) ->
+                        ({model | clockSync = Just clockSync}, Cmd.none)
+                    Ok (NewCapture capture) ->
+                        -- Only the readings of the capture are shown
+                        ({model | capture = Just capture, readings = []}, Cmd.none)
+                    Ok (Raw message) ->
+                        let
+                            _ = Debug.log "Raw message: " message
+                        in
+                            (model, Cmd.none)
                     Err e ->
                         let
                             _ = Debug.log "Error decoding message: " e
@@ -66,7 +90,7 @@ update msg model =
         TriggerChannelSet index ->
             ({model | triggerChannel = index}, Cmd.none)
         ResetValues ->
-            ({model | readings = []}, Cmd.none)
+            ({model | readings = [], gaps = []}, Cmd.none)
         MouseGlobalMove {clientPos} ->
             let
                 (newX, _) = clientPos
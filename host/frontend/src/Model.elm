@@ -1,6 +1,14 @@
 module Model exposing (Model, init, MouseDragReceiver(..))
 
-import Types exposing (Reading, TriggerMode(..))
+import Types exposing
+    ( Reading
+    , DeviceInfo
+    , Gap
+    , DeviceError
+    , ClockSync
+    , Capture
+    , TriggerMode(..)
+    )
 import TimeUnits exposing (Time, TimeUnit(..))
 import Msg exposing (Msg)
 
@@ -19,19 +27,31 @@ type alias Model =
     , mouseDragReceiver: Maybe MouseDragReceiver
     , lastDragPos: (Float, Float)
     , graphOffset: Float
+    , deviceInfo: Maybe DeviceInfo
+    , gaps: List Gap
+    , lostFrames: Int
+    , lastError: Maybe DeviceError
+    , clockSync: Maybe ClockSync
+    , capture: Maybe Capture
     }
 
 
 init : (Model, Cmd Msg)
 init =
     ( { readings = initialReadings
-      , currentReading = (Reading [False, False] 400)
+      , currentReading = (Reading [False, False] [False, False] 400)
       , triggerMode = FallingEdge
       , timeSpan = Time Millisecond 1
       , triggerChannel = 1
       , mouseDragReceiver = Nothing
       , lastDragPos = (0,0)
       , graphOffset = 0
+      , deviceInfo = Nothing
+      , gaps = []
+      , lostFrames = 0
+      , lastError = Nothing
+      , clockSync = Nothing
+      , capture = Nothing
     }
     , Cmd.none
     )
@@ -39,8 +59,8 @@ init =
 
 initialReadings : List Reading
 initialReadings =
-    [ Reading [False, False] 0
-    , Reading [True, False] 100
-    , Reading [False, True] 300
-    , Reading [True, True] 350
+    [ Reading [False, False] [False, False] 0
+    , Reading [True, False] [True, False] 100
+    , Reading [False, True] [True, True] 300
+    , Reading [True, True] [True, False] 350
     ]
@@ -1,6 +1,12 @@
 module Types exposing
     ( Message(..)
     , Reading
+    , DeviceInfo
+    , Gap
+    , LostFrames
+    , DeviceError
+    , ClockSync
+    , Capture
     , messageDecoder
     , readingsToChannels
     , TriggerMode(..)
@@ -13,29 +19,151 @@ import List.Extra
 
 type alias Reading =
     { values: List Bool
+    -- The channels that had an edge that caused the reading
+    , changed: List Bool
     , time: Float
     }
 
 
+type alias DeviceInfo =
+    { protocolVersion: Int
+    , firmwareVersion: String
+    , channelCount: Int
+    , timerFrequency: Int
+    , bufferSize: Int
+    }
+
+
+-- The device dropped readings, the signal is incomplete after `time`
+type alias Gap =
+    { dropped: Int
+    , time: Float
+    }
+
+
+-- Frames from the device that were lost on the way to the host
+type alias LostFrames =
+    { lost: Int
+    , totalLost: Int
+    }
+
+
+-- `code` is the name of the `ErrorCode`, the meaning of `detail` depends on it
+type alias DeviceError =
+    { code: String
+    , detail: Int
+    }
+
+
+-- The device read `deviceTime` at `hostTime` microseconds since the unix epoch
+type alias ClockSync =
+    { roundTrip: Float
+    , deviceTime: Float
+    , hostTime: Float
+    }
+
+
+-- A triggered capture, the readings in it follow
+type alias Capture =
+    { triggerTime: Float
+    , preTrigger: Int
+    , postTrigger: Int
+    }
+
+
 type Message
     = CurrentTime Float
     | NewReading Reading
+    | NewDeviceInfo DeviceInfo
+    | NewGap Gap
+    | NewLostFrames LostFrames
+    | NewDeviceError DeviceError
+    | NewClockSync ClockSync
+    | NewCapture Capture
+    -- A message as it was received from the device, the host sends these when
+    -- started with `--raw`
+    | Raw De.Value
 
 
 readingDecoder : De.Decoder Reading
 readingDecoder =
-    De.map2 Reading
+    De.map3 Reading
         (De.field "values" (De.list De.bool))
+        (De.field "changed" (De.list De.bool))
+        (De.field "time" De.float)
+
+
+deviceInfoDecoder : De.Decoder DeviceInfo
+deviceInfoDecoder =
+    De.map5 DeviceInfo
+        (De.field "protocol_version" De.int)
+        (De.field "firmware_version" De.string)
+        (De.field "channel_count" De.int)
+        (De.field "timer_frequency" De.int)
+        (De.field "buffer_size" De.int)
+
+
+gapDecoder : De.Decoder Gap
+gapDecoder =
+    De.map2 Gap
+        (De.field "dropped" De.int)
         (De.field "time" De.float)
 
 
+lostFramesDecoder : De.Decoder LostFrames
+lostFramesDecoder =
+    De.map2 LostFrames
+        (De.field "lost" De.int)
+        (De.field "total_lost" De.int)
+
+
+deviceErrorDecoder : De.Decoder DeviceError
+deviceErrorDecoder =
+    De.map2 DeviceError
+        (De.field "code" De.string)
+        (De.field "detail" De.int)
+
+
+clockSyncDecoder : De.Decoder ClockSync
+clockSyncDecoder =
+    De.map3 ClockSync
+        (De.field "round_trip" De.float)
+        (De.field "device_time" De.float)
+        (De.field "host_time" De.float)
+
+
+captureDecoder : De.Decoder Capture
+captureDecoder =
+    De.map3 Capture
+        (De.field "trigger_time" De.float)
+        (De.field "pre_trigger" De.int)
+        (De.field "post_trigger" De.int)
+
+
 messageDecoder : De.Decoder Message
 messageDecoder =
     let
         reading = De.map (\a -> NewReading a) <| De.field "Reading" readingDecoder
         currentTime = De.map (\a -> CurrentTime a) <| De.field "CurrentTime" De.float
+        deviceInfo = De.map (\a -> NewDeviceInfo a) <| De.field "DeviceInfo" deviceInfoDecoder
+        gap = De.map (\a -> NewGap a) <| De.field "Gap" gapDecoder
+        lostFrames = De.map (\a -> NewLostFrames a) <| De.field "LostFrames" lostFramesDecoder
+        deviceError = De.map (\a -> NewDeviceError a) <| De.field "DeviceError" deviceErrorDecoder
+        clockSync = De.map (\a -> NewClockSync a) <| De.field "ClockSync" clockSyncDecoder
+        capture = De.map (\a -> NewCapture a) <| De.field "Capture" captureDecoder
+        raw = De.map (\a -> Raw a) <| De.field "Raw" De.value
     in
-        De.oneOf [reading, currentTime]
+        De.oneOf
+            [ reading
+            , currentTime
+            , deviceInfo
+            , gap
+            , lostFrames
+            , deviceError
+            , clockSync
+            , capture
+            , raw
+            ]
 
 
 readingsToChannels : List Reading -> List (List (Float, Bool))
@@ -24,6 +24,11 @@ import Model exposing (Model)
 
 import Types exposing 
     ( TriggerMode(..)
+    , DeviceInfo
+    , Gap
+    , DeviceError
+    , ClockSync
+    , Capture
     , readingsToChannels
     , allTriggerModes
     , triggerModeSymbol
@@ -157,6 +162,16 @@ view model =
                 )
 
         buttonRow = [div [] [button [onClick ResetValues] [text "Reset"]]]
+
+        statusRow =
+            div [] <| List.map (\status -> div [] [text status])
+                [ deviceInfoStatus model.deviceInfo
+                , gapStatus model.gaps
+                , toString model.lostFrames ++ " frames lost on the way from the device"
+                , errorStatus model.lastError
+                , clockSyncStatus model.clockSync
+                , captureStatus model.capture
+                ]
     in
         contentContainer model
             <|  [ div [Style.class [Style.ButtonRow]]
@@ -169,6 +184,68 @@ view model =
                 (List.map graphFunction readings)
                 ++
                 buttonRow
+                ++
+                [statusRow]
+
+
+deviceInfoStatus : Maybe DeviceInfo -> String
+deviceInfoStatus deviceInfo =
+    case deviceInfo of
+        Just info ->
+            "Firmware "
+                ++ info.firmwareVersion
+                ++ ", "
+                ++ toString info.channelCount
+                ++ " channels, timer at "
+                ++ toString (info.timerFrequency // 1000000)
+                ++ " MHz"
+        Nothing ->
+            "Waiting for the device"
+
+
+gapStatus : List Gap -> String
+gapStatus gaps =
+    case List.reverse gaps of
+        latest :: _ ->
+            toString (List.sum <| List.map .dropped gaps)
+                ++ " readings dropped by the device, the latest at "
+                ++ toString latest.time
+                ++ " µs"
+        [] ->
+            "No readings dropped"
+
+
+errorStatus : Maybe DeviceError -> String
+errorStatus lastError =
+    case lastError of
+        Just error ->
+            "Device error: " ++ error.code ++ " (" ++ toString error.detail ++ ")"
+        Nothing ->
+            "No device errors"
+
+
+clockSyncStatus : Maybe ClockSync -> String
+clockSyncStatus clockSync =
+    case clockSync of
+        Just sync ->
+            "Round trip to the device: " ++ toString (round sync.roundTrip) ++ " µs"
+        Nothing ->
+            "Round trip to the device not measured yet"
+
+
+captureStatus : Maybe Capture -> String
+captureStatus capture =
+    case capture of
+        Just window ->
+            "Captured "
+                ++ toString window.preTrigger
+                ++ " readings before and "
+                ++ toString window.postTrigger
+                ++ " after the trigger at "
+                ++ toString window.triggerTime
+                ++ " µs"
+        Nothing ->
+            "No capture taken"
 
 
 
@@ -26,7 +26,7 @@ pub fn http_server() {
             Ok(response.body(content.into_bytes())?)
         }
         else {
-            let content = File::open(&format!("frontend/output/{}", request.uri()))
+            let content = File::open(format!("frontend/output/{}", request.uri()))
                 .and_then(|mut file| {
                     let mut content = String::new();
                     file.read_to_string(&mut content)?;
@@ -10,47 +10,291 @@ extern crate simple_server;
 
 
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, UNIX_EPOCH};
+use std::{env, thread};
 
 mod types;
+mod ping;
+mod stream;
 mod serial_reader;
 mod websockets;
 mod httpserver;
 
-use types::{RealReading, WebMessage, time_to_microseconds};
+use types::{
+    RealReading,
+    WebMessage,
+    DeviceInfo,
+    Gap,
+    LostFrames,
+    ClockSync,
+    Capture,
+    time_to_microseconds
+};
+use ping::PingTracker;
+use stream::DeviceStream;
 
-use api::data::{ClientHostMessage};
+use api::data::{
+    CaptureConfig,
+    ChannelEdgeMode,
+    ChannelMask,
+    ClientHostFrame,
+    ClientHostMessage,
+    EdgeMode,
+    HostClientMessage,
+    Pattern,
+    MAX_CHANNELS,
+    SampleConfig,
+    Trigger,
+    VarInt,
+    PROTOCOL_VERSION
+};
+use api::sequence::SequenceCheck;
+
+/**
+  How the device samples when started with `--sample-rate`. The period is only
+  known once the device has told us its timer frequency
+*/
+struct Sampling {
+    rate_hertz: u32,
+    // 0 to sample until stopped
+    blocks: u32,
+}
+
+/**
+  How the device is set up from the command line once it has told us about
+  itself
+*/
+struct DeviceConfig {
+    // `None` leaves the channels as they are
+    enabled_channels: Option<Vec<u8>>,
+    edge_modes: Vec<ChannelEdgeMode>,
+    capture: Option<CaptureConfig>,
+    sampling: Option<Sampling>,
+}
+
+impl DeviceConfig {
+    fn from_args() -> Self {
+        Self {
+            enabled_channels: enabled_channels(),
+            edge_modes: edge_modes(),
+            capture: capture(),
+            sampling: sampling(),
+        }
+    }
+}
 
 fn processing_thread(
-    hw_message_receiver: Receiver<ClientHostMessage>,
-    web_message_sender: Sender<WebMessage>
+    hw_message_receiver: Receiver<ClientHostFrame>,
+    web_message_sender: Sender<WebMessage>,
+    command_sender: Sender<HostClientMessage>,
+    forward_raw: bool,
+    config: DeviceConfig,
+    pings: Arc<Mutex<PingTracker>>
 ) {
+    // Readings are only requested once we know that the device speaks our protocol
+    command_sender.send(HostClientMessage::RequestInfo)
+        .expect("serial reader disconnected");
+
     let mut frequency = None;
+    let mut stream = DeviceStream::new();
+    let mut channel_lines = vec!();
+    let mut total_lost_frames = 0;
     loop {
-        let received = hw_message_receiver.recv()
+        let frame = hw_message_receiver.recv()
             .expect("sender disconnected");
 
+        match stream.check(&frame) {
+            SequenceCheck::InOrder => {}
+            SequenceCheck::Lost(lost) => {
+                total_lost_frames += lost as u64;
+                println!("Lost {} frames, {} in total", lost, total_lost_frames);
+                let message = WebMessage::LostFrames(LostFrames {
+                    lost: lost as u32,
+                    total_lost: total_lost_frames
+                });
+                web_message_sender.send(message).unwrap();
+            }
+            SequenceCheck::Duplicate => {
+                println!("Dropping duplicated frame {}", frame.sequence);
+                continue;
+            }
+        }
+
+        let received = frame.message;
+
+        // Pings are only sent once the device has been started
+        if frequency.is_some() {
+            let ping = pings.lock().unwrap().next_ping(Instant::now());
+            if let Some(ping) = ping {
+                command_sender.send(ping).expect("serial reader disconnected");
+            }
+        }
+
+        if forward_raw {
+            web_message_sender.send(WebMessage::Raw(Box::new(received.clone()))).unwrap();
+        }
+
         match received {
             ClientHostMessage::FrequencyHertz(val) => {
                 println!("Got frequency value: {}", val);
                 frequency = Some(val);
             },
-            ClientHostMessage::Reading(val) => {
-                if let Some(frequency) = frequency {
+            ClientHostMessage::Reading(_) | ClientHostMessage::CompactReading(_) => {
+                let reading = stream.readings.decode(&received);
+                if let (Some(frequency), Some(reading)) = (frequency, reading) {
+                    let ticks = stream.timestamps.extend(reading.time);
                     let message =
-                        WebMessage::Reading(RealReading::from_reading(frequency, val));
+                        WebMessage::Reading(RealReading::from_reading(frequency, reading, ticks));
+
+                    web_message_sender.send(message).unwrap();
+                }
+            },
+            ClientHostMessage::Readings(batch) => {
+                if let Some(frequency) = frequency {
+                    for reading in batch.readings() {
+                        let ticks = stream.timestamps.extend(reading.time);
+                        let message = WebMessage::Reading(
+                            RealReading::from_reading(frequency, *reading, ticks)
+                        );
+
+                        web_message_sender.send(message).unwrap();
+                    }
+                }
+            },
+            ClientHostMessage::Run(run) => {
+                if let Some(frequency) = frequency {
+                    for reading in run.readings() {
+                        let ticks = stream.timestamps.extend(reading.time);
+                        let message = WebMessage::Reading(
+                            RealReading::from_reading(frequency, reading, ticks)
+                        );
+
+                        web_message_sender.send(message).unwrap();
+                    }
+                }
+            },
+            ClientHostMessage::Samples(block) => {
+                if let Some(frequency) = frequency {
+                    let timestamps = &mut stream.timestamps;
+                    stream.samples.decode(&block, &channel_lines, |reading| {
+                        let ticks = timestamps.extend(reading.time);
+                        let message = WebMessage::Reading(
+                            RealReading::from_reading(frequency, reading, ticks)
+                        );
 
+                        web_message_sender.send(message).unwrap();
+                    });
+                }
+            },
+            ClientHostMessage::Triggered(window) => {
+                println!(
+                    "Trigger fired, capture of {} readings follows. Captures are only taken \
+                     once, the device streams readings again after it",
+                    window.pre_trigger.0 + 1 + window.post_trigger.0
+                );
+                let ticks = stream.timestamps.extend(window.trigger_time);
+                if let Some(frequency) = frequency {
+                    let message = WebMessage::Capture(Capture {
+                        trigger_time: time_to_microseconds(frequency, ticks),
+                        pre_trigger: window.pre_trigger.0,
+                        post_trigger: window.post_trigger.0,
+                    });
+                    web_message_sender.send(message).unwrap();
+                }
+            },
+            ClientHostMessage::Overflow(overflow) => {
+                println!("Device dropped {} readings", overflow.dropped);
+                let ticks = stream.timestamps.extend(overflow.at_time);
+                if let Some(frequency) = frequency {
+                    let message = WebMessage::Gap(Gap {
+                        dropped: overflow.dropped,
+                        time: time_to_microseconds(frequency, ticks)
+                    });
+                    web_message_sender.send(message).unwrap();
+                }
+            },
+            ClientHostMessage::Error(error) => {
+                println!("Device reported an error: {:?}", error);
+                web_message_sender.send(WebMessage::DeviceError(error)).unwrap();
+            },
+            ClientHostMessage::Pong(pong) => {
+                let ticks = stream.timestamps.extend(pong.device_ticks);
+                let round_trip = pings.lock().unwrap().receive_pong(&pong, Instant::now());
+                if let (Some(frequency), Some((round_trip, host_time))) = (frequency, round_trip) {
+                    let host_time = host_time.duration_since(UNIX_EPOCH)
+                        .expect("System time is before the unix epoch");
+                    let message = WebMessage::ClockSync(ClockSync {
+                        round_trip: round_trip.as_secs_f64() * 1_000_000.,
+                        device_time: time_to_microseconds(frequency, ticks),
+                        host_time: host_time.as_secs_f64() * 1_000_000.,
+                    });
                     web_message_sender.send(message).unwrap();
                 }
             },
             ClientHostMessage::Reset(_) => {
                 println!("Reset operation is not currently handled");
             },
+            ClientHostMessage::DeviceInfo(info) => {
+                println!("Got device info: {:?}", info);
+                if info.protocol_version != PROTOCOL_VERSION {
+                    println!(
+                        "Device uses protocol version {} but version {} is required, not starting",
+                        info.protocol_version,
+                        PROTOCOL_VERSION
+                    );
+                    frequency = None;
+                    continue;
+                }
+
+                frequency = Some(info.timer_frequency);
+                channel_lines = info.channel_lines.iter().cloned().collect();
+                web_message_sender.send(WebMessage::DeviceInfo(DeviceInfo::from_device_info(&info)))
+                    .unwrap();
+                if let Some(ref enabled_channels) = config.enabled_channels {
+                    for channel in 0..info.channel_count {
+                        let command = if enabled_channels.contains(&channel) {
+                            HostClientMessage::EnableChannel(channel)
+                        }
+                        else {
+                            HostClientMessage::DisableChannel(channel)
+                        };
+                        command_sender.send(command).expect("serial reader disconnected");
+                    }
+                }
+                for edge_mode in &config.edge_modes {
+                    command_sender.send(HostClientMessage::SetEdgeMode(edge_mode.clone()))
+                        .expect("serial reader disconnected");
+                }
+                if let Some(ref capture) = config.capture {
+                    if trigger_fits(&capture.trigger, info.channel_count) {
+                        command_sender.send(HostClientMessage::ArmTrigger(capture.clone()))
+                            .expect("serial reader disconnected");
+                    }
+                    else {
+                        println!(
+                            "The trigger uses channels above the {} of the device, not arming it",
+                            info.channel_count
+                        );
+                    }
+                }
+                let start = match config.sampling {
+                    Some(ref sampling) => HostClientMessage::StartSampling(SampleConfig {
+                        period: VarInt(info.timer_frequency / sampling.rate_hertz),
+                        blocks: VarInt(sampling.blocks),
+                    }),
+                    None => HostClientMessage::Start
+                };
+                command_sender.send(start)
+                    .expect("serial reader disconnected");
+            },
             ClientHostMessage::CurrentTime(time_u32) => {
+                // Always extended since these are what keep track of wraparounds
+                let ticks = stream.timestamps.extend(time_u32);
                 if let Some(frequency) = frequency {
                     let message = WebMessage::CurrentTime(time_to_microseconds(
                         frequency,
-                        time_u32
+                        ticks
                     ));
                     web_message_sender.send(message).unwrap();
                 }
@@ -59,14 +303,157 @@ fn processing_thread(
     }
 }
 
+/// The channels listed with `--channels=0,2`, or `None` to leave the device as it is
+fn enabled_channels() -> Option<Vec<u8>> {
+    let arg = env::args().find(|arg| arg.starts_with("--channels="))?;
+    let channels = arg["--channels=".len()..]
+        .split(',')
+        .map(|channel| {
+            channel.parse()
+                .expect("--channels takes a comma separated list of channel numbers")
+        })
+        .collect();
+    Some(channels)
+}
+
+/// The edge modes listed with `--edges=0:rising,1:both`
+fn edge_modes() -> Vec<ChannelEdgeMode> {
+    let arg = match env::args().find(|arg| arg.starts_with("--edges=")) {
+        Some(arg) => arg,
+        None => return vec!()
+    };
+    arg["--edges=".len()..]
+        .split(',')
+        .map(|setting| {
+            let mut parts = setting.splitn(2, ':');
+            let channel = parts.next().and_then(|channel| channel.parse().ok());
+            let mode = parts.next().and_then(parse_edge_mode);
+            match (channel, mode) {
+                (Some(channel), Some(mode)) => ChannelEdgeMode {channel, mode},
+                _ => panic!("--edges takes a comma separated list of channel:rising|falling|both")
+            }
+        })
+        .collect()
+}
+
+fn parse_edge_mode(mode: &str) -> Option<EdgeMode> {
+    match mode {
+        "rising" => Some(EdgeMode::Rising),
+        "falling" => Some(EdgeMode::Falling),
+        "both" => Some(EdgeMode::Both),
+        _ => None
+    }
+}
+
+/**
+  The capture set up with `--trigger=edge:0:rising` or `--trigger=pattern:1x0`,
+  where the pattern lists the value of each channel starting from channel 0 and
+  `x` means any value. The amount of readings before and after the trigger is
+  set with `--window=100:100`. The capture is only taken once
+*/
+fn capture() -> Option<CaptureConfig> {
+    const USAGE: &str = "--trigger takes edge:<channel>:rising|falling|both or pattern:<1|0|x...>";
+
+    let arg = env::args().find(|arg| arg.starts_with("--trigger="))?;
+    let mut parts = arg["--trigger=".len()..].splitn(2, ':');
+    let trigger = match (parts.next(), parts.next()) {
+        (Some("edge"), Some(edge)) => {
+            let mut parts = edge.splitn(2, ':');
+            let channel = parts.next().and_then(|channel| channel.parse().ok()).expect(USAGE);
+            let mode = parts.next().and_then(parse_edge_mode).expect(USAGE);
+            Trigger::Edge(ChannelEdgeMode {channel, mode})
+        }
+        (Some("pattern"), Some(pattern)) => {
+            if pattern.chars().count() > MAX_CHANNELS as usize {
+                panic!("--trigger patterns have at most {} channels", MAX_CHANNELS);
+            }
+            let (mut mask, mut value) = (0, 0);
+            for (channel, level) in pattern.chars().enumerate() {
+                match level {
+                    '1' => { mask |= 1 << channel; value |= 1 << channel; }
+                    '0' => mask |= 1 << channel,
+                    'x' => {}
+                    _ => panic!("{}", USAGE)
+                }
+            }
+            Trigger::Pattern(Pattern {mask: ChannelMask(mask), value: ChannelMask(value)})
+        }
+        _ => panic!("{}", USAGE)
+    };
+
+    let window = env::args()
+        .find(|arg| arg.starts_with("--window="))
+        .unwrap_or_else(|| "--window=100:100".to_string());
+    let mut parts = window["--window=".len()..].splitn(2, ':');
+    let mut count = || -> u32 {
+        parts.next()
+            .and_then(|count| count.parse().ok())
+            .expect("--window takes <readings before>:<readings after>")
+    };
+    let pre_trigger = count();
+    let post_trigger = count();
+
+    Some(CaptureConfig {
+        trigger,
+        pre_trigger: VarInt(pre_trigger),
+        post_trigger: VarInt(post_trigger),
+    })
+}
+
+/**
+  Fixed-rate sampling set up with `--sample-rate=1000000`, in hertz. By default
+  the device samples until it is reset, `--burst=16` stops after that many
+  blocks of samples. The device rejects bursts that do not fit in its RAM
+*/
+fn sampling() -> Option<Sampling> {
+    let arg = env::args().find(|arg| arg.starts_with("--sample-rate="))?;
+    let rate_hertz = arg["--sample-rate=".len()..]
+        .parse()
+        .ok()
+        .filter(|&rate| rate != 0)
+        .expect("--sample-rate takes a sample rate in hertz");
+    let blocks = env::args()
+        .find(|arg| arg.starts_with("--burst="))
+        .map(|arg| {
+            arg["--burst=".len()..]
+                .parse()
+                .expect("--burst takes an amount of sample blocks")
+        })
+        .unwrap_or(0);
+    Some(Sampling {rate_hertz, blocks})
+}
+
+/// Checks that `trigger` only uses channels that a device with `channel_count` channels has
+fn trigger_fits(trigger: &Trigger, channel_count: u8) -> bool {
+    match *trigger {
+        Trigger::Edge(ref edge) => edge.channel < channel_count,
+        Trigger::Pattern(ref pattern) => (pattern.mask.0 as u64) >> channel_count == 0,
+    }
+}
+
 fn main() {
     let (message_tx, message_rx) = channel();
     let (reading_tx, reading_rx) = channel();
+    let (command_tx, command_rx) = channel();
+    let forward_raw = env::args().any(|arg| arg == "--raw");
+    let config = DeviceConfig::from_args();
+    // Pings are created by the processing thread and timed by the serial reader
+    let pings = Arc::new(Mutex::new(PingTracker::new()));
+    let serial_pings = pings.clone();
 
 
     thread::spawn(httpserver::http_server);
-    thread::spawn(|| processing_thread(message_rx, reading_tx));
+    thread::spawn(move || processing_thread(
+        message_rx,
+        reading_tx,
+        command_tx,
+        forward_raw,
+        config,
+        pings
+    ));
     thread::spawn(|| websockets::server("0.0.0.0:8765", reading_rx));
 
-    serial_reader::serial_reader_thread(message_tx);
+    if let Err(e) = serial_reader::serial_reader_thread(message_tx, command_rx, serial_pings) {
+        println!("Serial reader stopped: {}", e);
+    }
 }
@@ -0,0 +1,151 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use api::data::{HostClientMessage, Pong};
+
+/// Time between round trip measurements
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+
+/**
+  Sends periodic pings to the device and matches the answers to them to
+  measure the round trip time.
+
+  Pings are created by the processing thread but only timed once the serial
+  reader has written them, so that the time spent waiting to be sent is not
+  counted as part of the round trip. Only the latest ping is kept track of,
+  pongs for older ones are ignored.
+*/
+pub struct PingTracker {
+    nonce: u32,
+    // When the ping with `nonce` was created, to space them out
+    created: Option<Instant>,
+    // When the ping with `nonce` was written, both monotonic and wall-clock
+    sent: Option<(Instant, SystemTime)>,
+}
+
+impl PingTracker {
+    pub fn new() -> Self {
+        Self {
+            nonce: 0,
+            created: None,
+            sent: None
+        }
+    }
+
+    /// Returns the next ping to send to the device if it is time for one
+    pub fn next_ping(&mut self, now: Instant) -> Option<HostClientMessage> {
+        if let Some(created) = self.created {
+            if now.duration_since(created) < PING_INTERVAL {
+                return None;
+            }
+        }
+
+        self.nonce = self.nonce.wrapping_add(1);
+        self.created = Some(now);
+        self.sent = None;
+        Some(HostClientMessage::Ping(self.nonce))
+    }
+
+    /// Starts timing the ping with `nonce`, which was written at `now`
+    pub fn ping_sent(&mut self, nonce: u32, now: Instant, wall_clock: SystemTime) {
+        if nonce == self.nonce {
+            self.sent = Some((now, wall_clock));
+        }
+    }
+
+    /**
+      Returns the round trip time of the ping answered by `pong`, which was
+      received at `now`, and the estimated wall-clock time at which the device
+      read `pong.device_ticks`, assuming that both directions take equally long
+    */
+    pub fn receive_pong(&mut self, pong: &Pong, now: Instant) -> Option<(Duration, SystemTime)> {
+        if pong.nonce != self.nonce {
+            return None;
+        }
+        let (sent, sent_wall_clock) = self.sent.take()?;
+        let round_trip = now.duration_since(sent);
+        Some((round_trip, sent_wall_clock + round_trip / 2))
+    }
+}
+
+
+#[cfg(test)]
+mod ping_tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn pong(nonce: u32) -> Pong {
+        Pong {nonce, device_ticks: 0}
+    }
+
+    fn nonce(ping: Option<HostClientMessage>) -> u32 {
+        match ping {
+            Some(HostClientMessage::Ping(nonce)) => nonce,
+            other => panic!("Expected a ping, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn pongs_are_matched_to_the_time_the_ping_was_written() {
+        let start = Instant::now();
+        let wall_clock = UNIX_EPOCH + Duration::from_secs(1000);
+        let mut pings = PingTracker::new();
+
+        let nonce = nonce(pings.next_ping(start));
+        // Waiting to be written does not count as round trip time
+        pings.ping_sent(nonce, start + ms(50), wall_clock);
+        assert_eq!(
+            pings.receive_pong(&pong(nonce), start + ms(70)),
+            Some((ms(20), wall_clock + ms(10)))
+        );
+        // Each ping is only answered once
+        assert_eq!(pings.receive_pong(&pong(nonce), start + ms(80)), None);
+    }
+
+    #[test]
+    fn pings_are_spaced_out() {
+        let start = Instant::now();
+        let mut pings = PingTracker::new();
+        assert_eq!(nonce(pings.next_ping(start)), 1);
+        assert_eq!(pings.next_ping(start + ms(999)), None);
+        assert_eq!(nonce(pings.next_ping(start + ms(1000))), 2);
+    }
+
+    #[test]
+    fn unknown_and_late_pongs_are_ignored() {
+        let start = Instant::now();
+        let mut pings = PingTracker::new();
+
+        // Nothing was sent yet
+        assert_eq!(pings.receive_pong(&pong(1), start), None);
+
+        let first = nonce(pings.next_ping(start));
+        // Not written yet
+        assert_eq!(pings.receive_pong(&pong(first), start), None);
+        pings.ping_sent(first, start, SystemTime::now());
+        assert_eq!(pings.receive_pong(&pong(first + 5), start + ms(10)), None);
+
+        // The answer to the first ping arrives after the second one was sent
+        let second = nonce(pings.next_ping(start + ms(1000)));
+        pings.ping_sent(first, start + ms(1000), SystemTime::now());
+        pings.ping_sent(second, start + ms(1000), SystemTime::now());
+        assert_eq!(pings.receive_pong(&pong(first), start + ms(1010)), None);
+        assert!(pings.receive_pong(&pong(second), start + ms(1010)).is_some());
+    }
+
+    #[test]
+    fn nonces_wrap_around() {
+        let start = Instant::now();
+        let mut pings = PingTracker::new();
+        pings.nonce = u32::MAX;
+
+        let nonce = nonce(pings.next_ping(start));
+        assert_eq!(nonce, 0);
+        pings.ping_sent(nonce, start, SystemTime::now());
+        assert!(pings.receive_pong(&pong(u32::MAX), start + ms(10)).is_none());
+        assert_eq!(pings.receive_pong(&pong(0), start + ms(10)).map(|(rtt, _)| rtt), Some(ms(10)));
+    }
+}
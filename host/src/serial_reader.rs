@@ -1,36 +1,134 @@
 use serial;
 
 use std::{env, io};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
 
 use serial::prelude::*;
-use std::sync::mpsc::Sender;
-use std::io::Write;
+use std::sync::mpsc::{Sender, Receiver};
 
 use api::data;
+use api::cobs::{self, Cobs};
 use api::Message;
 
-pub fn serial_reader_thread(reading_sender: Sender<data::ClientHostMessage>) {
-    let port_name = env::args_os().skip(1).next()
-        .expect("You need to specify a serial port");
-    let mut port = init_serial_port(&port_name)
-        .expect("Failed to open serial port");
-    let mut data_buffer: Vec<u8> = vec!();
+use ping::PingTracker;
 
+/**
+  How messages are delimited on the serial port. Must match the `cobs` feature
+  of the firmware
+*/
+#[derive(Clone, Copy)]
+enum Framing {
+    /// Messages start with a prefix byte, the default
+    Prefixed,
+    /// Messages are COBS stuffed and end with a zero byte
+    Cobs,
+}
+
+/// Size of the buffer used to find frames in the data read from the serial port
+const DECODER_BUFFER_SIZE: usize = 1024;
+
+pub fn serial_reader_thread(
+    reading_sender: Sender<data::ClientHostFrame>,
+    command_receiver: Receiver<data::HostClientMessage>,
+    pings: Arc<Mutex<PingTracker>>
+) -> Result<(), Box<dyn Error>> {
+    let framing = if env::args().any(|arg| arg == "--cobs") {
+        Framing::Cobs
+    }
+    else {
+        Framing::Prefixed
+    };
+    let port_name = env::args().skip(1).find(|arg| !arg.starts_with("--"))
+        .ok_or("You need to specify a serial port")?;
+    let port = init_serial_port(&port_name)?;
 
-    port.write(&[0]).unwrap();
+    match framing {
+        Framing::Prefixed => read_messages::<data::ClientHostFrame, _>(
+            port,
+            framing,
+            reading_sender,
+            command_receiver,
+            &pings,
+            |message| message
+        ),
+        Framing::Cobs => read_messages::<Cobs<data::ClientHostFrame>, _>(
+            port,
+            framing,
+            reading_sender,
+            command_receiver,
+            &pings,
+            |Cobs(message)| message
+        ),
+    }
+}
+
+/**
+  Sends pending commands and forwards messages from the serial port until either
+  channel is disconnected. Pings are timed in `pings` as they are written. `F`
+  is the framed message type, which is turned into the message itself by
+  `unwrap_frame`
+*/
+fn read_messages<F, P>(
+    mut port: P,
+    framing: Framing,
+    reading_sender: Sender<data::ClientHostFrame>,
+    command_receiver: Receiver<data::HostClientMessage>,
+    pings: &Mutex<PingTracker>,
+    unwrap_frame: fn(F) -> data::ClientHostFrame
+) -> Result<(), Box<dyn Error>>
+    where F: Message<F> + data::Resync,
+          P: SerialPort
+{
+    let mut decoder = data::StreamDecoder::<F, DECODER_BUFFER_SIZE>::new();
+    let mut corrupt_frames = 0;
+    let mut read_buffer = [0; 100];
 
     loop {
-        read_serial_port_data(&mut port, &mut data_buffer).unwrap();
-        let decoded = decode_messages(&mut data_buffer).unwrap();
-        for reading in decoded {
-            reading_sender.send(reading)
-                .expect("Reader disconnected");
+        for command in command_receiver.try_iter() {
+            send_command(&mut port, framing, &command)?;
+            // The round trip starts once the ping is on its way
+            if let data::HostClientMessage::Ping(nonce) = command {
+                pings.lock().unwrap().ping_sent(nonce, Instant::now(), SystemTime::now());
+            }
+        }
+
+        let read_amount = read_serial_port_data(&mut port, &mut read_buffer)?;
+        let mut pending = &read_buffer[..read_amount];
+        loop {
+            let pushed = decoder.push(pending);
+            pending = &pending[pushed..];
+
+            while let Some(event) = decoder.pull() {
+                match event {
+                    data::StreamEvent::Message(frame) => {
+                        let frame = unwrap_frame(frame);
+                        // States are sent without their width, which the
+                        // device info tells us
+                        if let data::ClientHostMessage::DeviceInfo(ref info) = frame.message {
+                            decoder.set_context(data::DecodingContext {
+                                channel_count: info.channel_count
+                            });
+                        }
+                        reading_sender.send(frame)?;
+                    }
+                    data::StreamEvent::Resync(error) => {
+                        corrupt_frames += 1;
+                        println!("{}, {} corrupt frames so far", error, corrupt_frames);
+                    }
+                }
+            }
+
+            if pending.is_empty() {
+                break;
+            }
         }
     }
 }
 
-fn init_serial_port(name: &::std::ffi::OsString) -> io::Result<serial::SystemPort> {
-    let mut port = serial::open(&name).unwrap();
+fn init_serial_port(name: &str) -> serial::Result<serial::SystemPort> {
+    let mut port = serial::open(name)?;
     port.reconfigure(&|settings| {
         //settings.set_baud_rate(serial::Baud115200)?;
         settings.set_baud_rate(serial::Baud115200)?;
@@ -41,49 +139,30 @@ fn init_serial_port(name: &::std::ffi::OsString) -> io::Result<serial::SystemPor
     Ok(port)
 }
 
-fn read_serial_port_data<T: SerialPort>(port: &mut T, buf: &mut Vec<u8>) -> io::Result<()> {
-    let mut internal_buf = [0; 100];
-    let read_amount = loop {
-        match port.read(&mut internal_buf) {
-            Ok(val) => break val,
-            Err(e) => {
-                match e.kind() {
-                    ::std::io::ErrorKind::TimedOut => continue,
-                    _ => return Err(e)
-                }
-            }
-        };
-    };
-
-    for b in internal_buf[..read_amount].iter() {
-        buf.push(*b);
-    }
-
+fn send_command<T: SerialPort>(
+    port: &mut T,
+    framing: Framing,
+    command: &data::HostClientMessage
+) -> Result<(), Box<dyn Error>> {
+    // Large enough for both framings
+    let mut buffer = [0; Cobs::<data::HostClientMessage>::MAX_ENCODED_LEN];
+    let byte_amount = match framing {
+        Framing::Prefixed => command.encode(&mut buffer),
+        Framing::Cobs => cobs::encode_message(command, &mut buffer),
+    }?;
+    port.write_all(&buffer[..byte_amount])?;
     Ok(())
 }
 
-fn decode_messages(data: &mut Vec<u8>)
-    -> Result<Vec<data::ClientHostMessage>, data::DecodingError>
-{
-    let mut result = vec!();
-    loop {
-        match data::ClientHostMessage::decode(data) {
-            Ok((bytes_used, reading)) => {
-                result.push(reading);
-                data.drain(0..bytes_used).collect::<Vec<_>>();
-            },
-            Err(data::DecodingError::EndOfBytes) => {
-                break;
-            }
-            Err(data::DecodingError::IncorrectPrefixByte(val)) => {
-                let dropped = data.remove(0);
-                println!("Got wrong prefix: {:x}, dropping byte", val);
-            }
-            Err(e) => {
-                return Err(e)
+fn read_serial_port_data<T: SerialPort>(port: &mut T, buf: &mut [u8]) -> io::Result<usize> {
+    match port.read(buf) {
+        Ok(val) => Ok(val),
+        Err(e) => {
+            match e.kind() {
+                // Give the caller a chance to send pending commands
+                ::std::io::ErrorKind::TimedOut => Ok(0),
+                _ => Err(e)
             }
         }
     }
-
-    Ok(result)
 }
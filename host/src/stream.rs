@@ -0,0 +1,142 @@
+use api::data::{ClientHostFrame, ClientHostMessage};
+use api::delta::ReadingDecoder;
+use api::sampling::SampleDecoder;
+use api::sequence::{SequenceCheck, SequenceTracker};
+use api::timestamp::TimestampUnwrapper;
+
+/**
+  The decoding state that depends on the frames received before, which has to
+  be forgotten when frames are lost or the device restarts.
+*/
+pub struct DeviceStream {
+    pub readings: ReadingDecoder,
+    pub samples: SampleDecoder,
+    pub timestamps: TimestampUnwrapper,
+    sequences: SequenceTracker,
+}
+
+impl DeviceStream {
+    pub fn new() -> Self {
+        Self {
+            readings: ReadingDecoder::new(),
+            samples: SampleDecoder::new(),
+            timestamps: TimestampUnwrapper::new(),
+            sequences: SequenceTracker::new(),
+        }
+    }
+
+    /**
+      Checks the sequence number of `frame`. Must be called for every frame
+      before its message is decoded
+    */
+    pub fn check(&mut self, frame: &ClientHostFrame) -> SequenceCheck {
+        // Device info is sent when the device starts and in answer to the
+        // request the host makes when it starts. Either way the frames before
+        // it belong to another run of the device, or there are none
+        if let ClientHostMessage::DeviceInfo(_) = frame.message {
+            self.sequences.reset();
+            self.timestamps.reset();
+            self.reset_decoders();
+        }
+
+        let check = self.sequences.check(frame.sequence);
+        if let SequenceCheck::Lost(_) = check {
+            // The lost frames may have held the readings that compact readings
+            // and samples are relative to
+            self.reset_decoders();
+        }
+        check
+    }
+
+    fn reset_decoders(&mut self) {
+        self.readings.reset();
+        self.samples.reset();
+    }
+}
+
+
+#[cfg(test)]
+mod stream_tests {
+    use super::*;
+    use api::data::{
+        ChannelMask,
+        CompactReading,
+        DeviceInfo,
+        Reading,
+        State,
+        VarInt
+    };
+
+    fn frame(sequence: u8, message: ClientHostMessage) -> ClientHostFrame {
+        ClientHostFrame {sequence, message}
+    }
+
+    fn reading(time: u32) -> ClientHostMessage {
+        ClientHostMessage::Reading(Reading::new(time, State::new(2, 0b01)))
+    }
+
+    fn compact(delta: u32) -> ClientHostMessage {
+        ClientHostMessage::CompactReading(CompactReading {
+            state: State::new(2, 0b10),
+            delta: VarInt(delta),
+            changed: ChannelMask(0b11)
+        })
+    }
+
+    fn device_info() -> ClientHostMessage {
+        ClientHostMessage::DeviceInfo(DeviceInfo::for_test(2))
+    }
+
+    /// Checks and decodes `frame` like the processing thread does
+    fn receive(stream: &mut DeviceStream, frame: ClientHostFrame) -> (SequenceCheck, Option<u32>) {
+        let check = stream.check(&frame);
+        let time = stream.readings.decode(&frame.message).map(|reading| reading.time);
+        (check, time)
+    }
+
+    #[test]
+    fn compact_readings_after_lost_frames_are_dropped() {
+        let mut stream = DeviceStream::new();
+        assert_eq!(receive(&mut stream, frame(0, reading(1000))), (SequenceCheck::InOrder, Some(1000)));
+        assert_eq!(receive(&mut stream, frame(1, compact(10))), (SequenceCheck::InOrder, Some(1010)));
+
+        // The reading in frame 2 is lost, so the delta is relative to an unknown time
+        assert_eq!(receive(&mut stream, frame(3, compact(10))), (SequenceCheck::Lost(1), None));
+        assert_eq!(receive(&mut stream, frame(4, compact(10))), (SequenceCheck::InOrder, None));
+        assert_eq!(receive(&mut stream, frame(5, reading(2000))), (SequenceCheck::InOrder, Some(2000)));
+    }
+
+    #[test]
+    fn restarted_devices_start_over() {
+        let mut stream = DeviceStream::new();
+        stream.check(&frame(0, device_info()));
+        for sequence in 1..100 {
+            stream.check(&frame(sequence, reading(0)));
+        }
+        // The old timer wraps around
+        stream.timestamps.extend(0x8000_0000);
+        stream.timestamps.extend(0xf000_0000);
+        assert_eq!(stream.timestamps.extend(0x1000), 0x1_0000_1000);
+
+        // The device restarts with fresh sequence numbers and timer
+        assert_eq!(stream.check(&frame(0, device_info())), SequenceCheck::InOrder);
+        assert_eq!(stream.timestamps.extend(0x1000), 0x1000);
+        assert_eq!(stream.check(&frame(1, reading(0x1100))), SequenceCheck::InOrder);
+    }
+
+    #[test]
+    fn restarts_in_the_middle_of_the_stream_are_detected() {
+        let mut stream = DeviceStream::new();
+        assert_eq!(receive(&mut stream, frame(200, reading(1000))), (SequenceCheck::InOrder, Some(1000)));
+        assert_eq!(receive(&mut stream, frame(201, compact(10))), (SequenceCheck::InOrder, Some(1010)));
+        stream.timestamps.extend(0xf000_0000);
+
+        // The device announces itself after restarting, frame 1 would
+        // otherwise look like a lost or duplicated frame
+        assert_eq!(receive(&mut stream, frame(0, device_info())), (SequenceCheck::InOrder, None));
+        assert_eq!(stream.timestamps.extend(0x1000), 0x1000);
+        // There is no reading from this run yet for the delta to be relative to
+        assert_eq!(receive(&mut stream, frame(1, compact(10))), (SequenceCheck::InOrder, None));
+        assert_eq!(receive(&mut stream, frame(2, reading(2000))), (SequenceCheck::InOrder, Some(2000)));
+    }
+}
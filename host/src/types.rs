@@ -3,24 +3,107 @@ use api::data;
 #[derive(Debug, Serialize)]
 pub struct RealReading {
     pub values: Vec<bool>,
+    /// Which of the channels had an edge that caused the reading
+    pub changed: Vec<bool>,
     pub time: f64 // In microseconds
 }
 
 impl RealReading {
-    pub fn from_reading(frequency_hertz: u32, reading: data::Reading) -> Self {
+    /// `ticks` is the time of the reading extended to 64 bits by a `TimestampUnwrapper`
+    pub fn from_reading(frequency_hertz: u32, reading: data::Reading, ticks: u64) -> Self {
         Self {
-            values: vec!(reading.state.channel1(), reading.state.channel2()),
-            time: time_to_microseconds(frequency_hertz, reading.time),
+            values: (0..reading.state.channel_count())
+                .map(|n| reading.state.channel(n))
+                .collect(),
+            changed: (0..reading.state.channel_count())
+                .map(|n| reading.changed.contains(n))
+                .collect(),
+            time: time_to_microseconds(frequency_hertz, ticks),
         }
     }
 }
 
-pub fn time_to_microseconds(frequency_hertz: u32, time: u32) -> f64 {
+pub fn time_to_microseconds(frequency_hertz: u32, time: u64) -> f64 {
     (time as f64) / (frequency_hertz / 1_000_000) as f64
 }
 
+/**
+  Marks a point where the device had to drop readings, the capture is incomplete
+  after `time`
+*/
+#[derive(Debug, Serialize)]
+pub struct Gap {
+    pub dropped: u32,
+    pub time: f64 // In microseconds
+}
+
+/**
+  Frames from the device were lost on the way, unlike a `Gap` the device does
+  not know which readings are missing
+*/
+#[derive(Debug, Serialize)]
+pub struct LostFrames {
+    pub lost: u32,
+    pub total_lost: u64, // Since the host was started
+}
+
+/**
+  Anchors device time to wall-clock time, `device_time` was read by the device
+  at `host_time`
+*/
+#[derive(Debug, Serialize)]
+pub struct ClockSync {
+    pub round_trip: f64, // In microseconds
+    pub device_time: f64, // In microseconds
+    pub host_time: f64, // Microseconds since the unix epoch
+}
+
+/**
+  A triggered capture, followed by the `pre_trigger + 1 + post_trigger`
+  readings in it
+*/
+#[derive(Debug, Serialize)]
+pub struct Capture {
+    pub trigger_time: f64, // In microseconds
+    pub pre_trigger: u32,
+    pub post_trigger: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeviceInfo {
+    pub protocol_version: u8,
+    pub firmware_version: String,
+    pub channel_count: u8,
+    pub timer_frequency: u32,
+    pub buffer_size: u32,
+    pub channel_lines: Vec<u8>,
+}
+
+impl DeviceInfo {
+    pub fn from_device_info(info: &data::DeviceInfo) -> Self {
+        let version = &info.firmware_version;
+        Self {
+            protocol_version: info.protocol_version,
+            firmware_version: format!("{}.{}.{}", version.major, version.minor, version.patch),
+            channel_count: info.channel_count,
+            timer_frequency: info.timer_frequency,
+            buffer_size: info.buffer_size,
+            channel_lines: info.channel_lines.iter().cloned().collect(),
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub enum WebMessage {
     Reading(RealReading),
-    CurrentTime(f64)
+    CurrentTime(f64),
+    DeviceInfo(DeviceInfo),
+    Gap(Gap),
+    LostFrames(LostFrames),
+    DeviceError(data::DeviceError),
+    ClockSync(ClockSync),
+    Capture(Capture),
+    /// A message exactly as it was received from the device, sent when the host
+    /// is started with `--raw`
+    Raw(Box<data::ClientHostMessage>),
 }
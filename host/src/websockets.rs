@@ -5,7 +5,6 @@ use std::sync::mpsc::Receiver;
 use std::sync::{Arc, Mutex};
 use std::net::TcpStream;
 use std::thread;
-use std::io::Write;
 
 use serde_json;
 
@@ -41,8 +40,9 @@ fn client_handler(clients: Arc<Mutex<Vec<Client<TcpStream>>>>, rx: Receiver<WebM
                     .expect("Failed to encode message")
             );
 
-            client.send_message(&message)
-                .map_err(|e| println!("Failed to send client {:?}", e));
+            if let Err(e) = client.send_message(&message) {
+                println!("Failed to send client {:?}", e);
+            }
         }
     }
 }
@@ -1,27 +1,113 @@
-use stm32f103xx::EXTI;
-
-macro_rules! enable_channel {
-    ($exti:ident, $mr:ident, $tr:ident) => {
-        // Block to make the macro valid in expression contexts
-        {
-            // Mask the exti
-            $exti.imr.modify(|_r, w| w.$mr().set_bit());
-            // Trigger on both rising and falling edge
-            $exti.rtsr.modify(|_r, w| w.$tr().set_bit());
-            $exti.ftsr.modify(|_r, w| w.$tr().set_bit());
-        }
-    }
-}
+use stm32f103xx::{AFIO, EXTI, GPIOA};
+
+use api::data::EdgeMode;
+
+pub const CHANNEL_COUNT: u8 = 9;
+
+/**
+  The GPIOA pin, and thereby the EXTI line, that each channel is connected to.
+  Channels 0 and 1 keep the pins of the original two channel board. PA2 and PA3
+  are used by the serial port.
+*/
+pub const CHANNEL_LINES: [u8; CHANNEL_COUNT as usize] = [8, 9, 0, 1, 4, 5, 6, 7, 10];
+
+// The EXTICR value that connects an EXTI line to GPIOA
+const SOURCE_GPIOA: u32 = 0b0000;
 
 pub enum Error {
     NoSuchChannel(u8)
 }
 
-pub fn enable_channel(exti: &EXTI, index: u8) -> Result<(), Error> {
-    match index {
-        0 => enable_channel!(exti, mr8, tr8),
-        1 => enable_channel!(exti, mr9, tr9),
-        _ => return Err(Error::NoSuchChannel(index))
+fn line(index: u8) -> Result<u32, Error> {
+    CHANNEL_LINES.get(index as usize)
+        .map(|line| 1 << line)
+        .ok_or(Error::NoSuchChannel(index))
+}
+
+/// Turns a word with bit `n` set for EXTI line `n` into a channel mask
+fn lines_to_channels(lines: u32) -> u32 {
+    CHANNEL_LINES.iter()
+        .enumerate()
+        .filter(|&(_, &line)| lines & (1 << line) != 0)
+        .fold(0u32, |mask, (channel, _)| mask | (1 << channel))
+}
+
+/**
+  Connects the EXTI line of every channel to its GPIOA pin. The AFIO clock
+  must be enabled
+*/
+pub fn select_sources() {
+    // The HAL takes ownership of AFIO without exposing the EXTICR registers
+    let afio = unsafe { &*AFIO::ptr() };
+    for &line in CHANNEL_LINES.iter() {
+        let shift = (line % 4) * 4;
+        let select = |bits: u32| (bits & !(0b1111 << shift)) | (SOURCE_GPIOA << shift);
+        match line / 4 {
+            0 => afio.exticr1.modify(|r, w| unsafe { w.bits(select(r.bits())) }),
+            1 => afio.exticr2.modify(|r, w| unsafe { w.bits(select(r.bits())) }),
+            2 => afio.exticr3.modify(|r, w| unsafe { w.bits(select(r.bits())) }),
+            _ => afio.exticr4.modify(|r, w| unsafe { w.bits(select(r.bits())) }),
+        }
     }
+}
+
+pub fn enable_channel(exti: &EXTI, index: u8, mode: EdgeMode) -> Result<(), Error> {
+    let line = line(index)?;
+    set_triggers(exti, line, mode);
+    // Unmask the interrupt
+    exti.imr.modify(|r, w| unsafe { w.bits(r.bits() | line) });
     Ok(())
 }
+
+/// Changes which edges of an enabled channel generate interrupts
+pub fn set_edge_mode(exti: &EXTI, index: u8, mode: EdgeMode) -> Result<(), Error> {
+    let line = line(index)?;
+    set_triggers(exti, line, mode);
+    Ok(())
+}
+
+fn set_triggers(exti: &EXTI, line: u32, mode: EdgeMode) {
+    let (rising, falling) = match mode {
+        EdgeMode::Rising => (true, false),
+        EdgeMode::Falling => (false, true),
+        EdgeMode::Both => (true, true),
+    };
+    let update = |bits: u32, enabled: bool| if enabled { bits | line } else { bits & !line };
+    exti.rtsr.modify(|r, w| unsafe { w.bits(update(r.bits(), rising)) });
+    exti.ftsr.modify(|r, w| unsafe { w.bits(update(r.bits(), falling)) });
+}
+
+/// Stops the channel from generating interrupts
+pub fn disable_channel(exti: &EXTI, index: u8) -> Result<(), Error> {
+    let line = line(index)?;
+    exti.imr.modify(|r, w| unsafe { w.bits(r.bits() & !line) });
+    exti.rtsr.modify(|r, w| unsafe { w.bits(r.bits() & !line) });
+    exti.ftsr.modify(|r, w| unsafe { w.bits(r.bits() & !line) });
+    // Drop an edge that arrived before the line was masked
+    exti.pr.write(|w| unsafe { w.bits(line) });
+    Ok(())
+}
+
+/**
+  Clears the pending interrupts of all channels and returns the channels which
+  had one as a mask where bit `n` is channel `n`
+*/
+pub fn take_pending(exti: &EXTI) -> u32 {
+    let pending = exti.pr.read().bits();
+    let lines = CHANNEL_LINES.iter()
+        .fold(0u32, |lines, &line| lines | (1 << line));
+
+    // Writing a one clears the flag, other lines are left alone so their
+    // interrupts are not lost
+    exti.pr.write(|w| unsafe { w.bits(pending & lines) });
+
+    lines_to_channels(pending)
+}
+
+/// Samples all channels, bit `n` of the result is the value of channel `n`
+pub fn read_state() -> u32 {
+    // The pins are owned by `init` which only configures them as inputs.
+    // Reading the whole input register samples every channel at the same time
+    let input = unsafe { (*GPIOA::ptr()).idr.read().bits() };
+    lines_to_channels(input)
+}
@@ -0,0 +1,127 @@
+use embedded_hal::serial;
+
+use api::Message;
+use api::data::{
+    self,
+    ClientHostFrame,
+    ClientHostMessage,
+    DeviceError,
+    EncodingError,
+    ErrorCode,
+    Tagged
+};
+
+#[cfg(feature = "cobs")]
+use api::cobs::{self, Cobs};
+
+/**
+  Encodes `message` in the frame numbered `sequence` using the framing selected
+  by the `cobs` feature, without copying the message into a `ClientHostFrame`
+*/
+#[cfg(not(feature = "cobs"))]
+fn encode_frame(sequence: u8, message: &ClientHostMessage, buffer: &mut [u8])
+    -> Result<usize, EncodingError>
+{
+    data::encode_client_host_frame(sequence, message, buffer)
+}
+
+#[cfg(feature = "cobs")]
+fn encode_frame(sequence: u8, message: &ClientHostMessage, buffer: &mut [u8])
+    -> Result<usize, EncodingError>
+{
+    cobs::encode_with(buffer, |buffer| data::encode_client_host_frame(sequence, message, buffer))
+}
+
+/**
+  The largest amount of bytes any message of type `M` takes up with the framing
+  selected by the `cobs` feature
+*/
+pub const fn max_encoded_len<M: Message<M>>() -> usize {
+    <Frame<M> as Message<Frame<M>>>::MAX_ENCODED_LEN
+}
+
+/**
+  The type a message is wrapped in when sent with the framing selected by the
+  `cobs` feature
+*/
+#[cfg(not(feature = "cobs"))]
+pub type Frame<M> = M;
+
+#[cfg(feature = "cobs")]
+pub type Frame<M> = Cobs<M>;
+
+/**
+  Extracts the message from a frame decoded with the framing selected by the
+  `cobs` feature
+*/
+#[cfg(not(feature = "cobs"))]
+pub fn unwrap_frame<M>(frame: Frame<M>) -> M {
+    frame
+}
+
+#[cfg(feature = "cobs")]
+pub fn unwrap_frame<M>(frame: Frame<M>) -> M {
+    frame.0
+}
+
+/// Large enough for any frame sent to the host
+const FRAME_BUFFER_SIZE: usize = max_encoded_len::<ClientHostFrame>();
+
+/**
+  Sends messages to the host in frames numbered by a rolling sequence counter.
+
+  Failures to send a message are reported to the host in a
+  `ClientHostMessage::Error` that is sent before the next message.
+*/
+pub struct Transmitter<W> {
+    tx: W,
+    sequence: u8,
+    pending_error: Option<DeviceError>,
+}
+
+impl<W: serial::Write<u8>> Transmitter<W> {
+    pub fn new(tx: W) -> Self {
+        Self {
+            tx,
+            sequence: 0,
+            pending_error: None
+        }
+    }
+
+    /// Sends `message` in the next frame, blocking until all of it is written
+    pub fn send(&mut self, message: &ClientHostMessage) {
+        if let Some(error) = self.pending_error.take() {
+            self.send_frame(&ClientHostMessage::Error(error));
+        }
+        self.send_frame(message);
+    }
+
+    /// Queues an error to be sent before the next message
+    pub fn report(&mut self, code: ErrorCode, detail: u32) {
+        // Later errors are likely caused by the first one, only it is kept
+        if self.pending_error.is_none() {
+            self.pending_error = Some(DeviceError {code, detail});
+        }
+    }
+
+    fn send_frame(&mut self, message: &ClientHostMessage) {
+        let mut buffer = [0; FRAME_BUFFER_SIZE];
+        let byte_amount = match encode_frame(self.sequence, message, &mut buffer) {
+            Ok(byte_amount) => byte_amount,
+            Err(_) => {
+                self.report(ErrorCode::Encode, message.tag() as u32);
+                return;
+            }
+        };
+        self.sequence = self.sequence.wrapping_add(1);
+
+        for byte in buffer[..byte_amount].iter() {
+            if block!(self.tx.write(*byte)).is_err() {
+                // The host drops the partial frame and notices the gap in
+                // sequence numbers
+                self.report(ErrorCode::UartWrite, 0);
+                return;
+            }
+        }
+    }
+}
@@ -1,12 +1,39 @@
-macro_rules! send_client_host_message {
-    ($message:expr, $byte_amount:expr, $tx:expr, $threshold:expr) => {
-        let mut buffer = [0; $byte_amount];
-        let byte_amount = $message.encode(&mut buffer).expect("Failed to encode message");
+/**
+  Declares the handler of an EXTI interrupt which records a reading of all
+  channels. The tasks of all EXTI interrupts have the same resources
+*/
+macro_rules! edge_handler {
+    ($name:ident, $task:ident) => {
+        fn $name(_t: &mut Threshold, mut r: $task::Resources) {
+            // Reset the interrupt flags, remembering which channels they belonged to
+            let enabled = r.ENABLED_CHANNELS.0;
+            let changed = ChannelMask(channels::take_pending(&r.EXTI) & enabled);
 
-        $tx.claim_mut($threshold, |tx, _| {
-            for byte in buffer[..byte_amount].iter() {
-                block!(tx.write(*byte)).unwrap()
+            // Another handler can already have taken the edges, and edges of a
+            // channel can still be pending right after it was disabled
+            if !*r.RUNNING || changed.0 == 0 {
+                return;
+            }
+
+            // Read the time
+            let time = r.MONO_TIMER.ticks_passed();
+
+            // Disabled channels always read as low so that noise on them is not sent
+            let state = State::new(channels::CHANNEL_COUNT, channels::read_state() & enabled);
+            let reading = Reading {state, time, changed};
+            if r.PRODUCER.enqueue(reading).is_err() {
+                // Keep going and let idle tell the host that readings are missing
+                let overflow = r.OVERFLOW.get_or_insert(Overflow{dropped: 0, at_time: time});
+                overflow.dropped = overflow.dropped.saturating_add(1);
             }
+        }
+    }
+}
+
+macro_rules! send_client_host_message {
+    ($message:expr, $tx:expr, $threshold:expr) => {
+        $tx.claim_mut($threshold, |tx, _| {
+            tx.send($message)
         })
     }
 }
@@ -1,4 +1,3 @@
-#![feature(proc_macro)]
 #![no_std]
 #![no_main]
 
@@ -17,10 +16,36 @@ extern crate arrayvec;
 
 extern crate api;
 
-use api::Message;
-
-use heapless::ring_buffer::{RingBuffer, Consumer, Producer};
-use api::data::{Reading, ClientHostMessage};
+use core::mem;
+
+use heapless::spsc::{Queue, Consumer, Producer};
+use api::delta::ReadingEncoder;
+use api::run::{RunEncoder, RunEvent};
+use api::trigger::{Capture, CaptureEvent};
+use api::data::{
+    Reading,
+    ReadingBatch,
+    SampleBlock,
+    SAMPLE_BLOCK_SIZE,
+    ChannelMask,
+    ChannelEdgeMode,
+    EdgeMode,
+    Overflow,
+    State,
+    ClientHostMessage,
+    HostClientMessage,
+    DecodingError,
+    DeviceError,
+    ErrorCode,
+    StreamDecoder,
+    StreamError,
+    StreamEvent,
+    DeviceInfo,
+    Pong,
+    Version,
+    MAX_HEARTBEAT_MILLIS,
+    PROTOCOL_VERSION
+};
 
 
 // use stm32f103xx_hal::flash::FlashExt;
@@ -29,7 +54,7 @@ use stm32f103xx_hal::time;
 use stm32f103xx_hal::timer;
 use stm32f103xx_hal::mono_timer;
 use stm32f103xx_hal::serial;
-use stm32f103xx_hal::gpio::{self, gpioa, gpioc};
+use stm32f103xx_hal::gpio::{self, gpioc};
 use embedded_hal_time::{Millisecond, RealCountDown, Stopwatch};
 use stm32f103xx::USART2 as HwUSART2;
 use stm32f103xx::EXTI;
@@ -43,47 +68,139 @@ use rtfm::{app, Threshold, Resource};
 #[macro_use]
 mod macros;
 mod channels;
+mod framing;
+mod sampling;
 // mod stopwatch;
 
 const BUFFER_SIZE: usize = 200;
+// The most readings a triggered capture can hold
+const CAPTURE_SIZE: usize = 256;
+// Sample blocks waiting to be sent. Holds a whole burst, the queue keeps one
+// slot free
+const SAMPLE_QUEUE_SIZE: usize = sampling::MAX_BURST_BLOCKS as usize + 1;
+// Large enough for any command the host can send
+const COMMAND_BUFFER_SIZE: usize = framing::max_encoded_len::<HostClientMessage>();
+// Answers to commands waiting to be sent
+const RESPONSE_QUEUE_SIZE: usize = 16;
+
+// Edges further apart than a millisecond are not sent as runs
+const MAX_RUN_PERIOD_HERTZ: u32 = 1000;
+// A running clock is sent as a run every 100 milliseconds
+const MAX_RUN_DURATION_HERTZ: u32 = 10;
 
 // Transmission timeout
 const CURRENT_TIME_SEND_RATE: Millisecond = Millisecond(10);
 
-static mut _RB: RingBuffer<Reading, [Reading; BUFFER_SIZE]> = RingBuffer::new();
+static mut _RB: Queue<Reading, BUFFER_SIZE> = Queue::new();
+static mut _SAMPLE_RB: Queue<SampleBlock, SAMPLE_QUEUE_SIZE> = Queue::new();
+static mut _RESPONSE_RB: Queue<Response, RESPONSE_QUEUE_SIZE> = Queue::new();
+
+/**
+  An answer to a command. They are sent by `idle` so that receiving commands is
+  never held up by a frame being written to the UART, which would overrun the
+  receiver
+*/
+enum Response {
+    DeviceInfo,
+    Pong(Pong),
+    Error(DeviceError),
+}
 
 app! {
     device: stm32f103xx,
 
     resources: {
-        static CONSUMER: Consumer<'static, Reading, [Reading; BUFFER_SIZE]>;
-        static PRODUCER: Producer<'static, Reading, [Reading; BUFFER_SIZE]>;
+        static CONSUMER: Consumer<'static, Reading, BUFFER_SIZE>;
+        static PRODUCER: Producer<'static, Reading, BUFFER_SIZE>;
+        static SAMPLE_CONSUMER: Consumer<'static, SampleBlock, SAMPLE_QUEUE_SIZE>;
+        static SAMPLE_PRODUCER: Producer<'static, SampleBlock, SAMPLE_QUEUE_SIZE>;
+        static RESPONSE_CONSUMER: Consumer<'static, Response, RESPONSE_QUEUE_SIZE>;
+        static RESPONSE_PRODUCER: Producer<'static, Response, RESPONSE_QUEUE_SIZE>;
+        static SAMPLER: sampling::Sampler;
+        // Readings dropped since the last overflow was reported
+        static OVERFLOW: Option<Overflow> = None;
         static MONO_TIMER: mono_timer::MonoTimer32bit<HwTIM3, HwTIM4>;
-        static TX: serial::Tx<HwUSART2>;
+        // Not used by `on_rx`, writing a frame would delay reading the next byte
+        static TX: framing::Transmitter<serial::Tx<HwUSART2>>;
         static RX: serial::Rx<HwUSART2>;
-        static PIN1: gpioa::PA8<gpio::Input<gpio::Floating>>;
-        static PIN2: gpioa::PA9<gpio::Input<gpio::Floating>>;
+        static COMMANDS: StreamDecoder<framing::Frame<HostClientMessage>, COMMAND_BUFFER_SIZE>;
+        static RUNNING: bool = false;
+        // Readings are collected here instead of being streamed while a trigger is armed
+        static CAPTURE: Capture<CAPTURE_SIZE> = Capture::new();
         static EXTI: EXTI;
+        // The channels that generate readings, all are enabled in `init`
+        static ENABLED_CHANNELS: ChannelMask = ChannelMask((1 << channels::CHANNEL_COUNT) - 1);
+        // Kept while a channel is disabled so that it is restored when enabled again
+        static EDGE_MODES: [EdgeMode; channels::CHANNEL_COUNT as usize] =
+            [EdgeMode::Both; channels::CHANNEL_COUNT as usize];
         static OUTPUT_PIN: gpioc::PC13<gpio::Output<gpio::PushPull>>;
         static FREQUENCY: time::Hertz;
         static TIMER2: timer::Timer<HwTIM2>;
     },
 
     idle: {
-        resources: [CONSUMER, TX, OUTPUT_PIN]
+        resources: [
+            CONSUMER,
+            SAMPLE_CONSUMER,
+            RESPONSE_CONSUMER,
+            TX,
+            OUTPUT_PIN,
+            OVERFLOW,
+            MONO_TIMER,
+            FREQUENCY,
+            CAPTURE
+        ]
     },
 
     tasks: {
+        // Every channel interrupt records the state of all channels
+        EXTI0: {
+            path: on_exti0,
+            resources: [PRODUCER, MONO_TIMER, EXTI, RUNNING, OVERFLOW, ENABLED_CHANNELS],
+            priority: 3,
+        },
+        EXTI1: {
+            path: on_exti1,
+            resources: [PRODUCER, MONO_TIMER, EXTI, RUNNING, OVERFLOW, ENABLED_CHANNELS],
+            priority: 3,
+        },
+        EXTI4: {
+            path: on_exti4,
+            resources: [PRODUCER, MONO_TIMER, EXTI, RUNNING, OVERFLOW, ENABLED_CHANNELS],
+            priority: 3,
+        },
         EXTI9_5: {
-            path: on_pin1,
-            resources: [PRODUCER, MONO_TIMER, PIN1, PIN2, EXTI],
+            path: on_exti9_5,
+            resources: [PRODUCER, MONO_TIMER, EXTI, RUNNING, OVERFLOW, ENABLED_CHANNELS],
+            priority: 3,
+        },
+        EXTI15_10: {
+            path: on_exti15_10,
+            resources: [PRODUCER, MONO_TIMER, EXTI, RUNNING, OVERFLOW, ENABLED_CHANNELS],
             priority: 3,
         },
         USART2: {
             path: on_rx,
-            resources: [EXTI, RX, TX, FREQUENCY],
+            resources: [
+                EXTI,
+                RX,
+                COMMANDS,
+                RESPONSE_PRODUCER,
+                RUNNING,
+                TIMER2,
+                MONO_TIMER,
+                ENABLED_CHANNELS,
+                EDGE_MODES,
+                CAPTURE,
+                SAMPLER
+            ],
             priority: 2
         },
+        DMA1_CHANNEL5: {
+            path: on_samples,
+            resources: [SAMPLER, SAMPLE_PRODUCER, OVERFLOW],
+            priority: 3,
+        },
         TIM2: {
             path: on_timer,
             resources: [TX, MONO_TIMER, TIMER2],
@@ -128,15 +245,30 @@ fn init(p: init::Peripherals) -> init::LateResources {
     let frequency = mono_timer.frequency();
 
 
-    // Configure pin a8 as a floating input
-    let pin1 = gpioa.pa8.into_floating_input(&mut gpioa.crh);
-    // Configure pin a9 as a floating input
-    let pin2 = gpioa.pa9.into_floating_input(&mut gpioa.crh);
-
-    channels::enable_channel(&p.device.EXTI, 0).map_err(|_e| panic!());
-    channels::enable_channel(&p.device.EXTI, 1).map_err(|_e| panic!());
+    // Configure the channel pins as floating inputs. They are read through
+    // the input register by `channels::read_state` so the pins are not kept
+    gpioa.pa0.into_floating_input(&mut gpioa.crl);
+    gpioa.pa1.into_floating_input(&mut gpioa.crl);
+    gpioa.pa4.into_floating_input(&mut gpioa.crl);
+    gpioa.pa5.into_floating_input(&mut gpioa.crl);
+    gpioa.pa6.into_floating_input(&mut gpioa.crl);
+    gpioa.pa7.into_floating_input(&mut gpioa.crl);
+    gpioa.pa8.into_floating_input(&mut gpioa.crh);
+    gpioa.pa9.into_floating_input(&mut gpioa.crh);
+    gpioa.pa10.into_floating_input(&mut gpioa.crh);
+
+    // The AFIO clock was enabled by `constrain`
+    channels::select_sources();
+    for channel in 0..channels::CHANNEL_COUNT {
+        channels::enable_channel(&p.device.EXTI, channel, EdgeMode::Both).map_err(|_e| panic!());
+    }
 
     let (producer, consumer) = unsafe{_RB.split()};
+    let (sample_producer, sample_consumer) = unsafe{_SAMPLE_RB.split()};
+    let (mut response_producer, response_consumer) = unsafe{_RESPONSE_RB.split()};
+    // Announcing the device lets a connected host notice that it restarted.
+    // The queue is empty so this always fits
+    let _ = response_producer.enqueue(Response::DeviceInfo);
 
     let mut output_pin = gpioc.pc13.into_push_pull_output(&mut gpioc.crh);
     output_pin.set_high();
@@ -144,11 +276,15 @@ fn init(p: init::Peripherals) -> init::LateResources {
     init::LateResources {
         CONSUMER: consumer,
         PRODUCER: producer,
+        SAMPLE_CONSUMER: sample_consumer,
+        SAMPLE_PRODUCER: sample_producer,
+        RESPONSE_CONSUMER: response_consumer,
+        RESPONSE_PRODUCER: response_producer,
+        SAMPLER: sampling::Sampler::new(p.device.TIM1, p.device.DMA1),
         MONO_TIMER: mono_timer,
-        TX: tx,
+        TX: framing::Transmitter::new(tx),
         RX: rx,
-        PIN1: pin1,
-        PIN2: pin2,
+        COMMANDS: StreamDecoder::new(),
         EXTI: p.device.EXTI,
         OUTPUT_PIN: output_pin,
         FREQUENCY: frequency,
@@ -157,74 +293,322 @@ fn init(p: init::Peripherals) -> init::LateResources {
 }
 
 fn idle(t: &mut Threshold, mut r: idle::Resources) -> ! {
+    // Readings are sent as deltas, the host drops them until it gets a full
+    // reading which happens at least every `FULL_READING_INTERVAL` readings
+    let mut encoder = ReadingEncoder::new();
+    // Periodic signals like clocks are sent as runs
+    let frequency = r.FREQUENCY.claim(t, |frequency, _| frequency.0);
+    let mut runs = RunEncoder::new(
+        frequency / MAX_RUN_PERIOD_HERTZ,
+        frequency / MAX_RUN_DURATION_HERTZ
+    );
+    // Bursts of readings are sent in a single frame
+    let mut batch = ReadingBatch::new();
     loop {
-        match r.CONSUMER.dequeue() {
+        // Answers to commands go first, the host can be waiting for them
+        while let Some(response) = r.RESPONSE_CONSUMER.dequeue() {
+            let message = match response {
+                Response::DeviceInfo => ClientHostMessage::DeviceInfo(device_info(frequency)),
+                Response::Pong(pong) => ClientHostMessage::Pong(pong),
+                Response::Error(error) => ClientHostMessage::Error(error),
+            };
+            r.TX.claim_mut(t, |tx, _| tx.send(&message));
+        }
+
+        let dequeued = r.CONSUMER.dequeue();
+        match dequeued {
             Some(reading) => {
                 r.OUTPUT_PIN.set_low();
-                let mut buffer = [0; 10];
-                let message = ClientHostMessage::Reading(reading);
-                let byte_amount = message.encode(&mut buffer).expect("Failed to encode reading");
-                r.OUTPUT_PIN.set_high();
-
-                //let mut tx = r.TX.lock_mut();
-                r.TX.claim_mut(t, |tx, _| {
-                    for byte in buffer[..byte_amount].iter() {
-                        block!(tx.write(*byte)).expect("Failed to send reading")
+                let captured = r.CAPTURE.claim_mut(t, |capture, _| {
+                    if capture.is_active() {
+                        capture.push(reading);
                     }
-                })
+                    capture.is_active()
+                });
+                if !captured {
+                    runs.push(reading);
+                }
             }
             None => {
-                rtfm::wfi();
+                let now = r.MONO_TIMER.claim(t, |mono_timer, _| mono_timer.ticks_passed());
+                runs.end_stale_run(now);
             }
         }
+
+        while let Some(event) = runs.pull() {
+            let message = match event {
+                RunEvent::Reading(reading) => {
+                    let _ = batch.push(reading);
+                    if !batch.is_full() {
+                        continue;
+                    }
+                    batch_message(&mut batch, &mut encoder)
+                }
+                RunEvent::Run(run) => {
+                    // Readings from before the run are sent first
+                    if !batch.is_empty() {
+                        let message = batch_message(&mut batch, &mut encoder);
+                        r.TX.claim_mut(t, |tx, _| tx.send(&message));
+                    }
+                    // The host does not use runs as a base for compact readings
+                    encoder.reset();
+                    ClientHostMessage::Run(run)
+                }
+            };
+
+            // The frame is encoded while holding the transmitter so that
+            // sequence numbers are sent in order
+            r.TX.claim_mut(t, |tx, _| tx.send(&message));
+        }
+
+        // Send a complete capture. New readings are dropped by the capture
+        // until all of it is sent, then they are streamed again
+        while let Some(event) = r.CAPTURE.claim_mut(t, |capture, _| capture.pull()) {
+            let message = match event {
+                CaptureEvent::Window(window) => {
+                    if !batch.is_empty() {
+                        let message = batch_message(&mut batch, &mut encoder);
+                        r.TX.claim_mut(t, |tx, _| tx.send(&message));
+                    }
+                    ClientHostMessage::Triggered(window)
+                }
+                CaptureEvent::Reading(reading) => {
+                    let _ = batch.push(reading);
+                    if !batch.is_full() {
+                        continue;
+                    }
+                    batch_message(&mut batch, &mut encoder)
+                }
+            };
+            r.TX.claim_mut(t, |tx, _| tx.send(&message));
+        }
+        r.OUTPUT_PIN.set_high();
+
+        while let Some(block) = r.SAMPLE_CONSUMER.dequeue() {
+            send_client_host_message!(&ClientHostMessage::Samples(block), r.TX, t);
+        }
+
+        if dequeued.is_none() && !batch.is_empty() {
+            // The burst is over
+            let message = batch_message(&mut batch, &mut encoder);
+            r.TX.claim_mut(t, |tx, _| tx.send(&message));
+        }
+
+        let overflow = r.OVERFLOW.claim_mut(t, |overflow, _| overflow.take());
+        if let Some(overflow) = overflow {
+            send_client_host_message!(
+                &ClientHostMessage::Overflow(overflow),
+                r.TX,
+                t
+            );
+        }
+
+        if dequeued.is_none() {
+            rtfm::wfi();
+        }
     }
 }
 
-fn on_pin1(_t: &mut Threshold, mut r: EXTI9_5::Resources) {
-    // Reset interrupt flag
-    r.EXTI.pr.modify(|_r, w| w.pr8().set_bit());
-    // Read the time
-    let time = r.MONO_TIMER.ticks_passed();
+/// Takes the readings out of `batch` and turns them into a message
+fn batch_message(batch: &mut ReadingBatch, encoder: &mut ReadingEncoder) -> ClientHostMessage {
+    let batch = mem::replace(batch, ReadingBatch::new());
+    if batch.len() == 1 {
+        encoder.encode(batch.readings()[0])
+    }
+    else {
+        // The host does not use batches as a base for compact readings
+        encoder.reset();
+        ClientHostMessage::Readings(batch)
+    }
+}
 
-    let reading = Reading::new(time, r.PIN1.is_high(), r.PIN2.is_high());
-    // TODO: Error handling
-    r.PRODUCER.enqueue(reading).expect("Failed to enqueue new reading");
+fn on_samples(_t: &mut Threshold, mut r: DMA1_CHANNEL5::Resources) {
+    let block = match r.SAMPLER.take_block() {
+        Some(block) => block,
+        None => return
+    };
+
+    let at_time = block.start_time;
+    if r.SAMPLE_PRODUCER.enqueue(block).is_err() {
+        // Samples are counted like readings, the host sees the gap either way
+        let overflow = r.OVERFLOW.get_or_insert(Overflow{dropped: 0, at_time});
+        overflow.dropped = overflow.dropped.saturating_add(SAMPLE_BLOCK_SIZE as u32);
+    }
 }
 
+edge_handler!(on_exti0, EXTI0);
+edge_handler!(on_exti1, EXTI1);
+edge_handler!(on_exti4, EXTI4);
+edge_handler!(on_exti9_5, EXTI9_5);
+edge_handler!(on_exti15_10, EXTI15_10);
+
 
 fn on_rx(t: &mut Threshold, mut r: USART2::Resources) {
-    // Read byte to reset state
-    let received = r.RX.read().unwrap();
+    let received = match r.RX.read() {
+        Ok(byte) => byte,
+        // Overrun or framing error, the host will have to resend the command
+        Err(_) => {
+            respond(&mut r, device_error(ErrorCode::UartRead, 0));
+            return;
+        }
+    };
 
-    send_client_host_message!(
-        &ClientHostMessage::FrequencyHertz(r.FREQUENCY.0),
-        10,
-        r.TX,
-        t
-    );
-    send_client_host_message!(
-        &ClientHostMessage::Reset(1),
-        10,
-        r.TX,
-        t
-    );
-    send_client_host_message!(
-        &ClientHostMessage::Reset(2),
-        10,
-        r.TX,
-        t
-    );
+    // A single byte always fits as long as all complete frames are pulled
+    // after every push
+    r.COMMANDS.push(&[received]);
+
+    while let Some(event) = r.COMMANDS.pull() {
+        match event {
+            StreamEvent::Message(frame) => {
+                handle_command(t, &mut r, framing::unwrap_frame(frame));
+            }
+            // Unknown and corrupt commands are dropped and reported to the host
+            StreamEvent::Resync(StreamError{error: DecodingError::UnexpectedByte(byte, _), ..}) => {
+                respond(&mut r, device_error(ErrorCode::UnknownCommand, byte as u32));
+            }
+            StreamEvent::Resync(error) => {
+                respond(&mut r, device_error(ErrorCode::CorruptCommand, error.skipped as u32));
+            }
+        }
+    }
+}
+
+fn handle_command(t: &mut Threshold, r: &mut USART2::Resources, command: HostClientMessage) {
+    match command {
+        HostClientMessage::Start => {
+            r.RUNNING.claim_mut(t, |running, _| **running = true);
+        }
+        HostClientMessage::Stop => {
+            r.RUNNING.claim_mut(t, |running, _| **running = false);
+        }
+        HostClientMessage::EnableChannel(index) => {
+            let mode = r.EDGE_MODES.get(index as usize).cloned().unwrap_or(EdgeMode::Both);
+            let result = r.EXTI.claim(t, |exti, _| channels::enable_channel(exti, index, mode));
+            match result {
+                Ok(()) => {
+                    r.ENABLED_CHANNELS.claim_mut(t, |enabled, _| enabled.0 |= 1 << index);
+                }
+                Err(channels::Error::NoSuchChannel(index)) => {
+                    respond(r, device_error(ErrorCode::NoSuchChannel, index as u32));
+                }
+            }
+        }
+        HostClientMessage::DisableChannel(index) => {
+            let result = r.EXTI.claim(t, |exti, _| channels::disable_channel(exti, index));
+            match result {
+                Ok(()) => {
+                    r.ENABLED_CHANNELS.claim_mut(t, |enabled, _| enabled.0 &= !(1 << index));
+                }
+                Err(channels::Error::NoSuchChannel(index)) => {
+                    respond(r, device_error(ErrorCode::NoSuchChannel, index as u32));
+                }
+            }
+        }
+        HostClientMessage::SetEdgeMode(ChannelEdgeMode {channel, mode}) => {
+            match r.EDGE_MODES.get_mut(channel as usize) {
+                Some(edge_mode) => {
+                    *edge_mode = mode;
+                    // Disabled channels get their mode when they are enabled
+                    let enabled = r.ENABLED_CHANNELS
+                        .claim(t, |enabled, _| enabled.contains(channel));
+                    if enabled {
+                        // The channel exists since it has an edge mode
+                        let _ = r.EXTI.claim(t, |exti, _| {
+                            channels::set_edge_mode(exti, channel, mode)
+                        });
+                    }
+                }
+                None => {
+                    respond(r, device_error(ErrorCode::NoSuchChannel, channel as u32));
+                }
+            }
+        }
+        HostClientMessage::RequestInfo => {
+            respond(r, Response::DeviceInfo);
+        }
+        HostClientMessage::ArmTrigger(config) => {
+            r.CAPTURE.arm(&config);
+        }
+        HostClientMessage::DisarmTrigger => {
+            r.CAPTURE.disarm();
+        }
+        HostClientMessage::StartSampling(config) => {
+            // Sampling replaces the readings of edges
+            r.RUNNING.claim_mut(t, |running, _| **running = false);
+            let now = r.MONO_TIMER.claim(t, |mono_timer, _| mono_timer.ticks_passed());
+            let result = r.SAMPLER.claim_mut(t, |sampler, _| {
+                sampler.start(config.period.0, config.blocks.0, now)
+            });
+            let error = match result {
+                Ok(()) => None,
+                Err(sampling::Error::InvalidPeriod(period)) => {
+                    Some(device_error(ErrorCode::InvalidSamplePeriod, period))
+                }
+                Err(sampling::Error::BurstTooLong(_)) => {
+                    Some(device_error(ErrorCode::SampleBurstTooLong, sampling::MAX_BURST_BLOCKS))
+                }
+            };
+            if let Some(error) = error {
+                respond(r, error);
+            }
+        }
+        HostClientMessage::StopSampling => {
+            r.SAMPLER.claim_mut(t, |sampler, _| sampler.stop());
+        }
+        HostClientMessage::SetHeartbeatRate(millis) => {
+            if millis == 0 || millis > MAX_HEARTBEAT_MILLIS {
+                respond(r, device_error(ErrorCode::InvalidArgument, millis));
+            }
+            else {
+                r.TIMER2.start_real(Millisecond(millis));
+            }
+        }
+        HostClientMessage::Ping(nonce) => {
+            let device_ticks = r.MONO_TIMER.claim(t, |mono_timer, _| mono_timer.ticks_passed());
+            respond(r, Response::Pong(Pong {nonce, device_ticks}));
+        }
+    }
+}
+
+
+/**
+  Queues `response` for `idle`. It is dropped if the host sends commands faster
+  than they are answered, which only a misbehaving host does
+*/
+fn respond(r: &mut USART2::Resources, response: Response) {
+    let _ = r.RESPONSE_PRODUCER.enqueue(response);
+}
+
+
+fn device_error(code: ErrorCode, detail: u32) -> Response {
+    Response::Error(DeviceError {code, detail})
+}
+
+
+fn device_info(timer_frequency: u32) -> DeviceInfo {
+    DeviceInfo {
+        protocol_version: PROTOCOL_VERSION,
+        firmware_version: Version {
+            major: env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+            minor: env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+            patch: env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+        },
+        channel_count: channels::CHANNEL_COUNT,
+        timer_frequency,
+        // The queue keeps one slot free
+        buffer_size: (BUFFER_SIZE - 1) as u32,
+        channel_lines: channels::CHANNEL_LINES.iter().cloned().collect(),
+    }
 }
 
 
 fn on_timer(t: &mut Threshold, mut r: TIM2::Resources) {
     // Reset the counter
-    r.TIMER2.wait();
+    r.TIMER2.claim_mut(t, |timer, _| timer.wait());
     let time = r.MONO_TIMER.claim(t, |mono_timer, _t| mono_timer.ticks_passed());
     //let time = 0x1234567e;
     send_client_host_message!(
         &ClientHostMessage::CurrentTime(time),
-        10,
         r.TX,
         t
     );
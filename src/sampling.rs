@@ -0,0 +1,157 @@
+use stm32f103xx::{DMA1, GPIOA, RCC, TIM1};
+
+use api::data::{SampleBlock, SAMPLE_BLOCK_SIZE};
+
+/// Faster sampling keeps the DMA busy enough to delay other bus accesses
+pub const MIN_PERIOD: u32 = 16;
+/// TIM1 is a 16 bit timer and samples at every overflow
+pub const MAX_PERIOD: u32 = 1 << 16;
+/**
+  The most blocks a burst can have. A burst is sampled faster than it can be
+  sent, so all of it has to fit in the queue of blocks waiting to be sent
+*/
+pub const MAX_BURST_BLOCKS: u32 = 16;
+
+// DMA channel configuration: 32 bit reads of the input register into 16 bit
+// samples, circular over both halves of the buffer with an interrupt per half
+const CCR_EN: u32 = 1 << 0;
+const CCR_TCIE: u32 = 1 << 1;
+const CCR_HTIE: u32 = 1 << 2;
+const CCR_CIRC: u32 = 1 << 5;
+const CCR_MINC: u32 = 1 << 7;
+const CCR_PSIZE_32: u32 = 0b10 << 8;
+const CCR_MSIZE_16: u32 = 0b01 << 10;
+const CCR_PL_HIGH: u32 = 0b10 << 12;
+
+// Channel 5 flags in the DMA interrupt registers
+const HTIF5: u32 = 1 << 18;
+const TCIF5: u32 = 1 << 17;
+const GIF5: u32 = 1 << 16;
+
+// TIM1 registers
+const CR1_CEN: u32 = 1 << 0;
+const DIER_UDE: u32 = 1 << 8;
+const EGR_UG: u32 = 1 << 0;
+
+// Written by the DMA, each half is read while the other one is being filled
+static mut BUFFER: [u16; 2 * SAMPLE_BLOCK_SIZE] = [0; 2 * SAMPLE_BLOCK_SIZE];
+
+pub enum Error {
+    InvalidPeriod(u32),
+    BurstTooLong(u32),
+}
+
+/**
+  Samples GPIOA at a fixed rate. Every TIM1 update triggers a transfer of the
+  input register on DMA1 channel 5 into a double buffer.
+
+  With the default clock configuration TIM1 counts at the same rate as the
+  monotonic timer, so periods are in the same ticks as reading times.
+*/
+pub struct Sampler {
+    timer: TIM1,
+    dma: DMA1,
+    period: u32,
+    // The time of the first sample of the next block
+    next_time: u32,
+    // Blocks left to sample, or `None` to sample until stopped
+    remaining: Option<u32>,
+}
+
+impl Sampler {
+    pub fn new(timer: TIM1, dma: DMA1) -> Self {
+        // The HAL takes ownership of RCC without exposing the enable bits of
+        // peripherals it does not drive
+        let rcc = unsafe { &*RCC::ptr() };
+        rcc.ahbenr.modify(|_r, w| w.dma1en().set_bit());
+        rcc.apb2enr.modify(|_r, w| w.tim1en().set_bit());
+
+        Self {
+            timer,
+            dma,
+            period: 0,
+            next_time: 0,
+            remaining: None,
+        }
+    }
+
+    /**
+      Starts sampling every `period` ticks, the first sample is taken one
+      period after `now`. `blocks` is the amount of blocks to take, or 0 to
+      sample until stopped
+    */
+    pub fn start(&mut self, period: u32, blocks: u32, now: u32) -> Result<(), Error> {
+        if period < MIN_PERIOD || period > MAX_PERIOD {
+            return Err(Error::InvalidPeriod(period));
+        }
+        if blocks > MAX_BURST_BLOCKS {
+            return Err(Error::BurstTooLong(blocks));
+        }
+        self.stop();
+
+        self.period = period;
+        // The timer requests the first transfer when it first overflows
+        self.next_time = now.wrapping_add(period);
+        self.remaining = if blocks == 0 { None } else { Some(blocks) };
+
+        let input = unsafe { &(*GPIOA::ptr()).idr as *const _ as u32 };
+        let buffer = unsafe { BUFFER.as_ptr() as u32 };
+        self.dma.ifcr.write(|w| unsafe { w.bits(GIF5) });
+        self.dma.cpar5.write(|w| unsafe { w.bits(input) });
+        self.dma.cmar5.write(|w| unsafe { w.bits(buffer) });
+        self.dma.cndtr5.write(|w| unsafe { w.bits(2 * SAMPLE_BLOCK_SIZE as u32) });
+        self.dma.ccr5.write(|w| unsafe {
+            w.bits(
+                CCR_MINC | CCR_CIRC | CCR_PSIZE_32 | CCR_MSIZE_16 | CCR_PL_HIGH
+                    | CCR_HTIE | CCR_TCIE | CCR_EN
+            )
+        });
+
+        self.timer.psc.write(|w| unsafe { w.bits(0) });
+        self.timer.arr.write(|w| unsafe { w.bits(period - 1) });
+        // Load the prescaler and period before starting
+        self.timer.egr.write(|w| unsafe { w.bits(EGR_UG) });
+        self.timer.dier.write(|w| unsafe { w.bits(DIER_UDE) });
+        self.timer.cr1.write(|w| unsafe { w.bits(CR1_CEN) });
+        Ok(())
+    }
+
+    pub fn stop(&mut self) {
+        self.timer.cr1.write(|w| unsafe { w.bits(0) });
+        self.timer.dier.write(|w| unsafe { w.bits(0) });
+        self.dma.ccr5.write(|w| unsafe { w.bits(0) });
+        self.dma.ifcr.write(|w| unsafe { w.bits(GIF5) });
+    }
+
+    /**
+      Returns the half of the buffer that was just filled. Called from the
+      DMA1 channel 5 interrupt
+    */
+    pub fn take_block(&mut self) -> Option<SampleBlock> {
+        let flags = self.dma.isr.read().bits();
+        self.dma.ifcr.write(|w| unsafe { w.bits(GIF5) });
+
+        let half = if flags & TCIF5 != 0 {
+            1
+        }
+        else if flags & HTIF5 != 0 {
+            0
+        }
+        else {
+            return None;
+        };
+
+        let start = half * SAMPLE_BLOCK_SIZE;
+        let samples = unsafe { &BUFFER[start..start + SAMPLE_BLOCK_SIZE] };
+        let block = SampleBlock::new(self.next_time, self.period, samples);
+        self.next_time = self.next_time
+            .wrapping_add(self.period.wrapping_mul(SAMPLE_BLOCK_SIZE as u32));
+
+        self.remaining = self.remaining.map(|remaining| remaining.saturating_sub(1));
+        if self.remaining == Some(0) {
+            self.stop();
+        }
+
+        Some(block)
+    }
+}